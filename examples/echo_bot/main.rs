@@ -0,0 +1,27 @@
+use p2p_chat_bot::{ChatNode, Config, Conversation, Event};
+
+// 使用库接口的最小机器人：把收到的私聊原样回给对方
+#[tokio::main]
+async fn main() -> std::io::Result<()> {
+    let config = Config { display_name: Some("echo".to_string()), profile_dir: "echo_profile".into(), ..Config::default() };
+    let (node, mut events) = ChatNode::builder().config(config).build().await?;
+    println!("Echo bot {} listening on {}", node.id(), node.address());
+
+    loop {
+        tokio::select! {
+            event = events.next() => match event {
+                Some(Event::MessageReceived { conversation: Conversation::Peer(sender), text, .. }) => {
+                    if let Err(e) = node.send(&sender, &text).await {
+                        eprintln!("Failed to echo to {}: {}", sender, e);
+                    }
+                },
+                Some(Event::PeerOnline { .. }) => println!("{} peer(s) online", node.peers().await.len()),
+                Some(_) => {},
+                None => break,
+            },
+            _ = tokio::signal::ctrl_c() => break,
+        }
+    }
+    node.shutdown().await;
+    Ok(())
+}
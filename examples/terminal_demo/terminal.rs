@@ -17,7 +17,7 @@ pub async fn run_terminal() -> io::Result<()> {
 }
 
 async fn process_command(input: &str) {
-    let args: Vec<&str> = input.trim().split_whitespace().collect();
+    let args: Vec<&str> = input.split_whitespace().collect();
     match args.first() {
         Some(&"HelloWorld") => commands::hello_world(),
         Some(&"SendMsg") if args.len() > 1 => commands::send_msg(&args[1..].join(" ")),
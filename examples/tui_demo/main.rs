@@ -0,0 +1,54 @@
+use crossterm::event::{self, Event, KeyCode, KeyEventKind};
+use ratatui::layout::{Constraint, Layout};
+use ratatui::style::{Modifier, Style};
+use ratatui::widgets::{Block, List, ListState, Paragraph};
+use std::io;
+
+// 演示聊天界面的布局：左侧会话列表，右侧消息和输入框
+fn main() -> io::Result<()> {
+    let peers = ["System", "Broadcast", "#lobby", "● alice", "● bob (2)", "○ carol"];
+    let messages = [
+        "[10:02:11] alice: hello",
+        "[10:02:15] me: hi alice",
+        "[10:03:40] alice: see you in #lobby",
+    ];
+    let mut selected = 3;
+    let mut input = String::new();
+
+    let mut terminal = ratatui::init();
+    let result = loop {
+        let drawn = terminal.draw(|frame| {
+            let [sidebar, main] = Layout::horizontal([Constraint::Length(24), Constraint::Min(20)]).areas(frame.area());
+            let [pane, input_area] = Layout::vertical([Constraint::Min(3), Constraint::Length(3)]).areas(main);
+            let mut state = ListState::default().with_selected(Some(selected));
+            frame.render_stateful_widget(
+                List::new(peers)
+                    .block(Block::bordered().title("Peers"))
+                    .highlight_style(Style::default().add_modifier(Modifier::REVERSED)),
+                sidebar,
+                &mut state,
+            );
+            frame.render_widget(Paragraph::new(messages.join("\n")).block(Block::bordered().title(peers[selected])), pane);
+            frame.render_widget(Paragraph::new(input.as_str()).block(Block::bordered().title("Tab to switch, Esc to quit")), input_area);
+        });
+        if let Err(e) = drawn {
+            break Err(e);
+        }
+        match event::read() {
+            Ok(Event::Key(key)) if key.kind == KeyEventKind::Press => match key.code {
+                KeyCode::Esc => break Ok(()),
+                KeyCode::Tab => selected = (selected + 1) % peers.len(),
+                KeyCode::Enter => input.clear(),
+                KeyCode::Backspace => {
+                    input.pop();
+                },
+                KeyCode::Char(c) => input.push(c),
+                _ => {},
+            },
+            Ok(_) => {},
+            Err(e) => break Err(e),
+        }
+    };
+    ratatui::restore();
+    result
+}
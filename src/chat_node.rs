@@ -0,0 +1,268 @@
+// chat_node.rs
+use log::{error, info, warn};
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::sync::Arc;
+use tokio::net::UdpSocket;
+use tokio::sync::{broadcast, mpsc, Mutex};
+use tokio::task::JoinHandle;
+use crate::config::Config;
+use crate::events::Event;
+use crate::identity::Identity;
+use crate::multicast_discovery;
+use crate::network::{self, Interface};
+use crate::node_manager::{NodeManager, Peer, SendOutcome};
+use crate::profile::Profile;
+use crate::udp_connection::{self, ChatSockets};
+
+// The chat sockets and the interfaces discovery runs on
+pub struct Transport {
+    // One per chosen interface, or a single one for all interfaces
+    sockets: Vec<(UdpSocket, Option<Interface>)>,
+    interfaces: Vec<Interface>,
+    // Address announced to peers
+    ip: Ipv4Addr,
+}
+
+impl Transport {
+    // Bind the chat socket and pick interfaces as the config says
+    pub async fn bind(config: &Config) -> io::Result<Self> {
+        let interfaces = network::select_interfaces(&config.interfaces)?;
+        for iface in &interfaces {
+            info!("Using interface {} ({})", iface.name, iface.ip);
+        }
+        let sockets = match (config.bind_addr, interfaces.as_slice()) {
+            (Some(ip), _) => vec![(UdpSocket::bind((ip, config.port)).await?, None)],
+            (None, []) => vec![(UdpSocket::bind((Ipv4Addr::UNSPECIFIED, config.port)).await?, None)],
+            // 每个选定的网卡各绑定一个聊天套接字，端口相同，收发都不经过其他网卡
+            (None, chosen) => {
+                let mut port = config.port;
+                let mut sockets = Vec::new();
+                for iface in chosen {
+                    let socket = network::chat_socket(iface, port)?;
+                    port = socket.local_addr()?.port();
+                    sockets.push((socket, Some(iface.clone())));
+                }
+                sockets
+            },
+        };
+        let ip = announced_ip(&sockets[0].0, &interfaces)?;
+        let mut transport = Transport { sockets, interfaces, ip };
+        if transport.ip.is_unspecified() {
+            // 套接字绑定在 0.0.0.0 上且没有指定网卡时，探测实际可路由的本机地址
+            let target = config.multicast_addr.parse()
+                .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "Invalid multicast address"))?;
+            transport.ip = match network::detect_local_ip(target).await {
+                Ok(ip) => ip,
+                Err(e) => {
+                    error!("Failed to detect local address, falling back to loopback: {}", e);
+                    Ipv4Addr::LOCALHOST
+                }
+            };
+        }
+        Ok(transport)
+    }
+
+    // Use a socket bound elsewhere; an empty interface list means all interfaces
+    pub fn with_socket(socket: UdpSocket, interfaces: Vec<Interface>) -> io::Result<Self> {
+        let ip = announced_ip(&socket, &interfaces)?;
+        Ok(Transport { sockets: vec![(socket, None)], interfaces, ip })
+    }
+}
+
+// The socket's own address, or the first interface's when it is bound to all of them
+fn announced_ip(socket: &UdpSocket, interfaces: &[Interface]) -> io::Result<Ipv4Addr> {
+    match (socket.local_addr()?, interfaces.first()) {
+        (SocketAddr::V4(addr), _) if !addr.ip().is_unspecified() => Ok(*addr.ip()),
+        (SocketAddr::V4(_), Some(iface)) => Ok(iface.ip),
+        (SocketAddr::V4(_), None) => Ok(Ipv4Addr::UNSPECIFIED),
+        (SocketAddr::V6(_), _) => Err(io::Error::new(io::ErrorKind::InvalidInput, "the chat socket must be IPv4")),
+    }
+}
+
+#[derive(Default)]
+pub struct ChatNodeBuilder {
+    config: Option<Config>,
+    identity: Option<Identity>,
+    transport: Option<Transport>,
+}
+
+impl ChatNodeBuilder {
+    pub fn config(mut self, config: Config) -> Self {
+        self.config = Some(config);
+        self
+    }
+
+    // Defaults to the identity stored in the profile directory, created on first use
+    pub fn identity(mut self, identity: Identity) -> Self {
+        self.identity = Some(identity);
+        self
+    }
+
+    // Defaults to Transport::bind with the node's config
+    pub fn transport(mut self, transport: Transport) -> Self {
+        self.transport = Some(transport);
+        self
+    }
+
+    // Start listening, discovery and announcements. The returned stream was
+    // subscribed before anything started, so it sees every event.
+    pub async fn build(self) -> io::Result<(ChatNode, EventStream)> {
+        let config = self.config.unwrap_or_default();
+        config.validate()?;
+        let config = Arc::new(config);
+        let profile = Profile::open(&config.profile_dir)?;
+        let identity = match self.identity {
+            Some(identity) => identity,
+            None => Identity::load_or_create(&profile)?,
+        };
+        let identity = Arc::new(identity);
+        info!("Loaded identity {} from {}", identity.node_id(), profile.dir().display());
+        let transport = match self.transport {
+            Some(transport) => transport,
+            None => Transport::bind(&config).await?,
+        };
+        let Transport { sockets, interfaces, ip } = transport;
+        let port = sockets[0].0.local_addr()?.port();
+        let sockets = ChatSockets::new(sockets);
+
+        let node_manager = Arc::new(Mutex::new(NodeManager::new(ip, port, sockets.clone(), Arc::clone(&identity), profile.clone(), &config)?));
+        let events = EventStream { events: node_manager.lock().await.subscribe() };
+        let mut tasks = Vec::new();
+
+        // 监听任务，每个聊天套接字一个
+        let (tx, mut rx) = mpsc::channel(100);
+        for socket in sockets.sockets() {
+            let tx = tx.clone();
+            tasks.push(tokio::spawn(async move {
+                if let Err(e) = udp_connection::start_listening(socket, tx).await {
+                    error!("Chat socket failed: {}", e);
+                }
+            }));
+        }
+        drop(tx);
+        let manager = Arc::clone(&node_manager);
+        tasks.push(tokio::spawn(async move {
+            while let Some((message_data, src)) = rx.recv().await {
+                // 处理每条消息时锁定 node_manager
+                manager.lock().await.process_message(message_data, src).await;
+            }
+        }));
+        let monitor = multicast_discovery::network_monitor(Arc::clone(&node_manager), identity.node_id(), Arc::clone(&config), interfaces.clone());
+        tasks.push(tokio::spawn(async move {
+            if let Err(e) = monitor.await {
+                error!("Discovery stopped: {}", e);
+            }
+        }));
+        let presence = node_manager.lock().await.presence();
+        let sender = multicast_discovery::multicast_sender(Arc::clone(&config), ip.to_string(), port, Arc::clone(&identity), interfaces.clone(), presence);
+        tasks.push(tokio::spawn(async move {
+            if let Err(e) = sender.await {
+                error!("Announcements stopped: {}", e);
+            }
+        }));
+
+        let node = ChatNode { node_manager, identity, config, profile, interfaces, address: SocketAddrV4::new(ip, port), tasks };
+        Ok((node, events))
+    }
+}
+
+// A running chat node. Dropping it stops nothing; call shutdown to tell the
+// other peers we are leaving.
+pub struct ChatNode {
+    node_manager: Arc<Mutex<NodeManager>>,
+    identity: Arc<Identity>,
+    config: Arc<Config>,
+    profile: Profile,
+    interfaces: Vec<Interface>,
+    address: SocketAddrV4,
+    tasks: Vec<JoinHandle<()>>,
+}
+
+impl ChatNode {
+    pub fn builder() -> ChatNodeBuilder {
+        ChatNodeBuilder::default()
+    }
+
+    // Our UUID
+    pub fn id(&self) -> String {
+        self.identity.node_id()
+    }
+
+    // Address and port peers reach us on
+    pub fn address(&self) -> SocketAddrV4 {
+        self.address
+    }
+
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    pub(crate) fn profile(&self) -> &Profile {
+        &self.profile
+    }
+
+    pub(crate) fn node_manager(&self) -> Arc<Mutex<NodeManager>> {
+        Arc::clone(&self.node_manager)
+    }
+
+    pub(crate) fn interfaces(&self) -> &[Interface] {
+        &self.interfaces
+    }
+
+    // Another subscriber for everything that happens from now on: peers coming
+    // and going, messages received
+    pub async fn events(&self) -> EventStream {
+        EventStream { events: self.node_manager.lock().await.subscribe() }
+    }
+
+    // Send a direct message to a peer given by alias, display name or UUID
+    // prefix. Messages to offline peers are queued.
+    pub async fn send(&self, peer: &str, text: &str) -> Result<SendOutcome, String> {
+        let manager = self.node_manager.lock().await;
+        let uuid = manager.resolve_peer(peer).await?;
+        manager.send_message(&uuid, text).await
+    }
+
+    // Peers that are online
+    pub async fn peers(&self) -> Vec<Peer> {
+        self.node_manager.lock().await.peers().await
+    }
+
+    // Give a peer a local name, returning its UUID
+    pub async fn set_alias(&self, peer: &str, alias: &str) -> Result<String, String> {
+        let manager = self.node_manager.lock().await;
+        let uuid = manager.resolve_peer(peer).await?;
+        manager.update_node_alias(&uuid, alias.to_string()).await?;
+        Ok(uuid)
+    }
+
+    // Tell the other peers we are going offline and stop all tasks
+    pub async fn shutdown(self) {
+        if let Err(e) = multicast_discovery::send_goodbye(&self.config, *self.address.ip(), self.address.port(), &self.identity, &self.interfaces).await {
+            error!("Failed to send goodbye: {:?}", e);
+        }
+        for task in self.tasks {
+            task.abort();
+        }
+    }
+}
+
+// Events of one subscriber. A subscriber that falls too far behind skips
+// the events it missed.
+pub struct EventStream {
+    events: broadcast::Receiver<Event>,
+}
+
+impl EventStream {
+    // None once the node is gone
+    pub async fn next(&mut self) -> Option<Event> {
+        loop {
+            match self.events.recv().await {
+                Ok(event) => return Some(event),
+                Err(broadcast::error::RecvError::Lagged(missed)) => warn!("Missed {} events", missed),
+                Err(broadcast::error::RecvError::Closed) => return None,
+            }
+        }
+    }
+}
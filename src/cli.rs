@@ -0,0 +1,99 @@
+use clap::Parser;
+use std::io;
+use std::net::Ipv4Addr;
+use std::path::{self, PathBuf};
+use p2p_chat_bot::Config;
+
+#[derive(Parser, Debug)]
+#[clap(name = "P2PChatBot", version, author = "SwartzMss", about = "LAN chat terminal")]
+pub struct Cli {
+    /// Display name for this node
+    #[clap(short, long)]
+    pub name: Option<String>,
+
+    /// Status text shown to other users next to the name
+    #[clap(short, long)]
+    pub status: Option<String>,
+
+    /// Local address to bind the chat socket to
+    #[clap(short, long)]
+    pub bind: Option<Ipv4Addr>,
+
+    /// Port for the chat socket (0 picks a free one)
+    #[clap(short, long)]
+    pub port: Option<u16>,
+
+    /// Multicast group used for discovery, e.g. 239.255.255.250:3000
+    #[clap(short, long)]
+    pub multicast: Option<String>,
+
+    /// Interface name or IPv4 address to use; repeat for several
+    #[clap(short, long = "interface")]
+    pub interfaces: Vec<String>,
+
+    /// Path of the config file
+    #[clap(short, long)]
+    pub config: Option<PathBuf>,
+
+    /// Path of the log4rs config file
+    #[clap(long)]
+    pub log_config: Option<PathBuf>,
+
+    /// Directory holding the identity key and other local state
+    #[clap(long)]
+    pub profile_dir: Option<PathBuf>,
+
+    /// Keep sent and received messages in the profile directory
+    #[clap(long)]
+    pub history: bool,
+
+    /// Run without the interactive terminal until interrupted
+    #[clap(long)]
+    pub headless: bool,
+
+    /// Use the full-screen interface instead of the line-based terminal
+    #[clap(long, conflicts_with = "headless")]
+    pub tui: bool,
+}
+
+impl Cli {
+    // The binary changes into its own directory on start, so relative paths
+    // given on the command line are anchored to where it was launched from
+    pub fn resolve_paths(&mut self) -> io::Result<()> {
+        for path in [&mut self.config, &mut self.log_config, &mut self.profile_dir].into_iter().flatten() {
+            *path = path::absolute(&*path)?;
+        }
+        Ok(())
+    }
+
+    // 命令行参数优先级最高，覆盖配置文件和环境变量
+    pub fn apply(&self, config: &mut Config) {
+        if let Some(name) = &self.name {
+            config.display_name = Some(name.clone());
+        }
+        if let Some(status) = &self.status {
+            config.status = Some(status.clone());
+        }
+        if let Some(bind) = self.bind {
+            config.bind_addr = Some(bind);
+        }
+        if let Some(port) = self.port {
+            config.port = port;
+        }
+        if let Some(multicast) = &self.multicast {
+            config.multicast_addr = multicast.clone();
+        }
+        if !self.interfaces.is_empty() {
+            config.interfaces = self.interfaces.clone();
+        }
+        if let Some(log_config) = &self.log_config {
+            config.log_config = log_config.clone();
+        }
+        if let Some(profile_dir) = &self.profile_dir {
+            config.profile_dir = profile_dir.clone();
+        }
+        if self.history {
+            config.history = true;
+        }
+    }
+}
@@ -0,0 +1,738 @@
+// command_registry.rs
+use chrono::{Days, Local, NaiveDate, TimeZone};
+use std::collections::HashMap;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use crate::commands::CommandHandler;
+use crate::console::output;
+use crate::history::SearchQuery;
+
+pub type CommandFuture = Pin<Box<dyn Future<Output = ()> + Send>>;
+
+// What an argument holds; drives validation and tab completion
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ArgKind {
+    Peer,
+    Room,
+    Number,
+    Date, // YYYY-MM-DD, local time
+    Text,
+    // Message body: the rest of the line exactly as typed, never split into words
+    Message,
+    // Name of another command
+    Command,
+    // One of a fixed set of words
+    Keyword(&'static [&'static str]),
+}
+
+impl ArgKind {
+    fn placeholder(&self) -> &'static str {
+        match self {
+            ArgKind::Peer => "peer",
+            ArgKind::Room => "room",
+            ArgKind::Number => "n",
+            ArgKind::Date => "YYYY-MM-DD",
+            ArgKind::Text | ArgKind::Message => "text",
+            ArgKind::Command => "command",
+            ArgKind::Keyword(_) => "word",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Arg {
+    name: &'static str,
+    kind: ArgKind,
+    required: bool,
+    // Takes all remaining words, e.g. a message body
+    rest: bool,
+}
+
+impl Arg {
+    const fn required(name: &'static str, kind: ArgKind) -> Self {
+        Arg { name, kind, required: true, rest: false }
+    }
+
+    const fn optional(name: &'static str, kind: ArgKind) -> Self {
+        Arg { name, kind, required: false, rest: false }
+    }
+
+    const fn rest(name: &'static str, kind: ArgKind) -> Self {
+        Arg { name, kind, required: true, rest: true }
+    }
+
+    fn usage(&self) -> String {
+        match (self.kind, self.required, self.rest) {
+            (ArgKind::Keyword(words), false, _) => format!("[{}]", words.join("|")),
+            (ArgKind::Keyword(words), true, _) => words.join("|"),
+            (_, _, true) => format!("<{}...>", self.name),
+            (_, true, false) => format!("<{}>", self.name),
+            (_, false, false) => format!("[{}]", self.name),
+        }
+    }
+
+    fn check(&self, value: &str) -> Result<(), String> {
+        match self.kind {
+            ArgKind::Number if value.trim_start_matches('#').parse::<u64>().is_err() => {
+                Err(format!("{} must be a number, got {:?}", self.name, value))
+            },
+            ArgKind::Date => local_midnight(value, 0).map(|_| ()),
+            ArgKind::Keyword(words) if !words.contains(&value) => {
+                Err(format!("Expected {} instead of {:?}", words.join(" or "), value))
+            },
+            _ => Ok(()),
+        }
+    }
+}
+
+// Arguments of one command line, checked against the command's schema
+pub struct Invocation {
+    args: Vec<String>,
+    options: HashMap<&'static str, String>,
+}
+
+impl Invocation {
+    fn arg(&self, index: usize) -> Option<&str> {
+        self.args.get(index).map(String::as_str)
+    }
+
+    // A required argument; parse has made sure it is there
+    fn string(&self, index: usize) -> String {
+        self.args[index].clone()
+    }
+
+    // Argument `index` and everything after it
+    fn rest(&self, index: usize) -> String {
+        self.args[index..].join(" ")
+    }
+
+    fn option(&self, name: &str) -> Option<&str> {
+        self.options.get(name).map(String::as_str)
+    }
+
+    fn number(&self, index: usize, default: u64) -> u64 {
+        self.arg(index).and_then(|n| n.trim_start_matches('#').parse().ok()).unwrap_or(default)
+    }
+}
+
+pub struct Command {
+    pub name: &'static str,
+    pub aliases: &'static [&'static str],
+    args: &'static [Arg],
+    // --name <value> options, accepted anywhere after the command name
+    options: &'static [Arg],
+    // First line is the summary shown by `help`, the rest only by `help <command>`
+    help: &'static str,
+    run: fn(Arc<CommandHandler>, Invocation) -> Result<CommandFuture, String>,
+}
+
+impl Command {
+    pub fn usage(&self) -> String {
+        let mut usage = vec![self.name.to_string()];
+        usage.extend(self.options.iter().map(|option| format!("[--{} <{}>]", option.name, option.kind.placeholder())));
+        usage.extend(self.args.iter().map(Arg::usage));
+        usage.join(" ")
+    }
+
+    fn summary(&self) -> &'static str {
+        self.help.lines().next().unwrap_or("")
+    }
+
+    fn option(&self, word: &str) -> Option<&Arg> {
+        let name = word.strip_prefix("--")?;
+        self.options.iter().find(|option| option.name == name)
+    }
+
+    // Split the words after the command name into options and positional
+    // arguments and check them against the schema
+    fn parse(&self, words: &[String]) -> Result<Invocation, String> {
+        let mut invocation = Invocation { args: Vec::new(), options: HashMap::new() };
+        let mut words = words.iter();
+        while let Some(word) = words.next() {
+            if self.options.is_empty() || !word.starts_with("--") {
+                invocation.args.push(word.clone());
+                continue;
+            }
+            let option = self.option(word).ok_or_else(|| format!("Unknown option {}", word))?;
+            let value = words.next().ok_or_else(|| format!("{} needs a value", word))?;
+            option.check(value)?;
+            invocation.options.insert(option.name, value.clone());
+        }
+
+        let takes_rest = self.args.last().is_some_and(|arg| arg.rest);
+        if !takes_rest && invocation.args.len() > self.args.len() {
+            return Err("Too many arguments".to_string());
+        }
+        for (index, arg) in self.args.iter().enumerate() {
+            match invocation.args.get(index) {
+                Some(value) => arg.check(value)?,
+                None if arg.required => return Err(format!("Missing {}", arg.usage())),
+                None => {},
+            }
+        }
+        Ok(invocation)
+    }
+
+    // The kind of the argument `previous` words into the line are followed by
+    fn expected(&self, previous: &[&str]) -> Option<ArgKind> {
+        let mut index = 0;
+        let mut words = previous.iter();
+        while let Some(word) = words.next() {
+            if let Some(option) = self.option(word) {
+                if words.next().is_none() {
+                    return Some(option.kind);
+                }
+                continue;
+            }
+            index += 1;
+        }
+        match self.args.get(index) {
+            Some(arg) => Some(arg.kind),
+            None => self.args.last().filter(|arg| arg.rest).map(|arg| arg.kind),
+        }
+    }
+}
+
+macro_rules! task {
+    ($body:expr) => {
+        Ok(Box::pin(async move { $body; }))
+    };
+}
+
+static COMMANDS: &[Command] = &[
+    Command {
+        name: "help",
+        aliases: &["?"],
+        args: &[Arg::optional("command", ArgKind::Command)],
+        options: &[],
+        help: "List commands, or show how to use one",
+        run: |_, invocation| {
+            let command = invocation.arg(0).map(str::to_string);
+            task!(print_help(command.as_deref()))
+        },
+    },
+    Command {
+        name: "list_users",
+        aliases: &["users"],
+        args: &[],
+        options: &[],
+        help: "List peers that are online",
+        run: |handler, _| task!(handler.list_users().await),
+    },
+    Command {
+        name: "send_message",
+        aliases: &["msg"],
+        args: &[Arg::required("peer", ArgKind::Peer), Arg::rest("message", ArgKind::Message)],
+        options: &[],
+        help: "Send a direct message\n\
+               The peer is an alias, a display name or a UUID prefix. Messages to a peer\n\
+               that is offline are queued until it comes back. Everything after the peer is\n\
+               sent as typed, except that \\n and \\t start a new line or insert a tab and\n\
+               \\\\ is a backslash: send_message bob line one\\nline two",
+        run: |handler, invocation| {
+            let peer = invocation.string(0);
+            // 收件人之后的所有内容都是消息正文
+            let message = unescape(&invocation.rest(1));
+            task!(handler.send_message(&peer, &message).await)
+        },
+    },
+    Command {
+        name: "broadcast",
+        aliases: &["shout"],
+        args: &[Arg::rest("message", ArgKind::Message)],
+        options: &[],
+        help: "Send a message to every peer online",
+        run: |handler, invocation| {
+            let message = unescape(&invocation.rest(0));
+            task!(handler.broadcast(&message).await)
+        },
+    },
+    Command {
+        name: "join",
+        aliases: &[],
+        args: &[Arg::required("room", ArgKind::Room), Arg::optional("passphrase", ArgKind::Text)],
+        options: &[],
+        help: "Join a room, creating it if nobody uses it yet\n\
+               With a passphrase the room is private: its messages are encrypted with a\n\
+               key derived from the passphrase and only peers using the same one can read them.",
+        run: |handler, invocation| {
+            let room = invocation.string(0);
+            // 带口令时加入加密的私有房间
+            let passphrase = invocation.arg(1).map(str::to_string);
+            task!(handler.join_room(&room, passphrase.as_deref()).await)
+        },
+    },
+    Command {
+        name: "leave",
+        aliases: &[],
+        args: &[Arg::required("room", ArgKind::Room)],
+        options: &[],
+        help: "Leave a room",
+        run: |handler, invocation| {
+            let room = invocation.string(0);
+            task!(handler.leave_room(&room).await)
+        },
+    },
+    Command {
+        name: "rooms",
+        aliases: &[],
+        args: &[],
+        options: &[],
+        help: "List known rooms and their online members",
+        run: |handler, _| task!(handler.list_rooms().await),
+    },
+    Command {
+        name: "say",
+        aliases: &[],
+        args: &[Arg::required("room", ArgKind::Room), Arg::rest("message", ArgKind::Message)],
+        options: &[],
+        help: "Send a message to a room you have joined",
+        run: |handler, invocation| {
+            let room = invocation.string(0);
+            let message = unescape(&invocation.rest(1));
+            task!(handler.say(&room, &message).await)
+        },
+    },
+    Command {
+        name: "room_history",
+        aliases: &[],
+        args: &[Arg::required("room", ArgKind::Room), Arg::optional("n", ArgKind::Number)],
+        options: &[],
+        help: "Show the last n messages of a room (20 by default)",
+        run: |handler, invocation| {
+            let room = invocation.string(0);
+            let count = invocation.number(1, 20) as usize;
+            task!(handler.room_history(&room, count).await)
+        },
+    },
+    Command {
+        name: "history",
+        aliases: &[],
+        args: &[Arg::required("peer", ArgKind::Peer), Arg::optional("n", ArgKind::Number)],
+        options: &[],
+        help: "Show the last n stored messages with a peer (20 by default)\n\
+               Needs history to be enabled with --history or in the config file.",
+        run: |handler, invocation| {
+            let peer = invocation.string(0);
+            let count = invocation.number(1, 20) as usize;
+            task!(handler.history(&peer, count).await)
+        },
+    },
+    Command {
+        name: "search",
+        aliases: &[],
+        args: &[Arg::rest("words", ArgKind::Text)],
+        options: &[
+            Arg::optional("from", ArgKind::Peer),
+            Arg::optional("room", ArgKind::Room),
+            Arg::optional("since", ArgKind::Date),
+            Arg::optional("until", ArgKind::Date),
+            Arg::optional("limit", ArgKind::Number),
+        ],
+        help: "Search stored messages for all the given words, newest first\n\
+               Dates are in local time and both ends are inclusive; 20 matches are shown\n\
+               unless --limit says otherwise. Needs history to be enabled.",
+        run: |handler, invocation| {
+            let query = SearchQuery {
+                text: invocation.rest(0),
+                sender: None,
+                room: invocation.option("room").map(str::to_string),
+                since: invocation.option("since").map(|date| local_midnight(date, 0)).transpose()?,
+                until: invocation.option("until").map(|date| local_midnight(date, 1)).transpose()?,
+                limit: invocation.option("limit").and_then(|n| n.parse().ok()).unwrap_or(20),
+            };
+            let from = invocation.option("from").map(str::to_string);
+            task!(handler.search(query, from.as_deref()).await)
+        },
+    },
+    Command {
+        name: "pending",
+        aliases: &[],
+        args: &[Arg::optional("cancel", ArgKind::Keyword(&["cancel"])), Arg::optional("id", ArgKind::Number)],
+        options: &[],
+        help: "List messages queued for offline peers, or cancel one by its number",
+        run: |handler, invocation| match invocation.arg(0) {
+            None => task!(handler.list_pending().await),
+            Some(_) => {
+                if invocation.arg(1).is_none() {
+                    return Err("Missing the number of the message to cancel".to_string());
+                }
+                let id = invocation.number(1, 0);
+                task!(handler.cancel_pending(id).await)
+            },
+        },
+    },
+    Command {
+        name: "ping",
+        aliases: &[],
+        args: &[Arg::required("peer", ArgKind::Peer)],
+        options: &[],
+        help: "Check that a peer answers",
+        run: |handler, invocation| {
+            let peer = invocation.string(0);
+            task!(handler.ping(&peer).await)
+        },
+    },
+    Command {
+        name: "interfaces",
+        aliases: &[],
+        args: &[],
+        options: &[],
+        help: "List local network interfaces, marking the ones in use",
+        run: |handler, _| task!(handler.list_interfaces()),
+    },
+    Command {
+        name: "fingerprint",
+        aliases: &[],
+        args: &[Arg::optional("peer", ArgKind::Peer)],
+        options: &[],
+        help: "Show the key fingerprint of this node or of a peer\n\
+               Compare fingerprints over another channel before running verify.",
+        run: |handler, invocation| {
+            let peer = invocation.arg(0).map(str::to_string);
+            task!(handler.fingerprint(peer.as_deref()).await)
+        },
+    },
+    Command {
+        name: "verify",
+        aliases: &[],
+        args: &[Arg::required("peer", ArgKind::Peer)],
+        options: &[],
+        help: "Mark the key of a peer as verified",
+        run: |handler, invocation| {
+            let peer = invocation.string(0);
+            task!(handler.verify(&peer).await)
+        },
+    },
+    Command {
+        name: "info",
+        aliases: &[],
+        args: &[Arg::required("peer", ArgKind::Peer)],
+        options: &[],
+        help: "Show everything known about a peer",
+        run: |handler, invocation| {
+            let peer = invocation.string(0);
+            task!(handler.info(&peer).await)
+        },
+    },
+    Command {
+        name: "remove",
+        aliases: &[],
+        args: &[Arg::required("peer", ArgKind::Peer)],
+        options: &[],
+        help: "Forget a peer until it announces itself again",
+        run: |handler, invocation| {
+            let peer = invocation.string(0);
+            task!(handler.remove(&peer).await)
+        },
+    },
+    Command {
+        name: "update_alias",
+        aliases: &["alias"],
+        args: &[Arg::required("peer", ArgKind::Peer), Arg::required("alias", ArgKind::Text)],
+        options: &[],
+        help: "Give a peer a local name that can be used instead of its UUID",
+        run: |handler, invocation| {
+            let peer = invocation.string(0);
+            let alias = invocation.string(1);
+            task!(handler.update_alias(&peer, &alias).await)
+        },
+    },
+    Command {
+        name: "exit",
+        aliases: &["quit"],
+        args: &[],
+        options: &[],
+        help: "Tell the other peers we are leaving and quit",
+        // 由输入循环处理，这里不会被执行
+        run: |_, _| Ok(Box::pin(async {})),
+    },
+];
+
+pub fn find(name: &str) -> Option<&'static Command> {
+    COMMANDS.iter().find(|command| command.name == name || command.aliases.contains(&name))
+}
+
+pub fn is_exit(line: &str) -> bool {
+    let mut words = line.split_whitespace();
+    match (words.next().and_then(find), words.next()) {
+        (Some(command), None) => command.name == "exit",
+        _ => false,
+    }
+}
+
+// Split a typed line, check it against the command's schema and start the command
+pub fn dispatch(line: &str, handler: Arc<CommandHandler>) -> Result<CommandFuture, String> {
+    let args = split_line(line).map_err(|e| format!("Invalid input: {}", e))?;
+    let Some(name) = args.first() else {
+        return Ok(Box::pin(async {}));
+    };
+    let command = find(name).ok_or_else(|| format!("Unknown command {:?}. Type 'help' to list commands.", name))?;
+    command.parse(&args[1..])
+        .and_then(|invocation| (command.run)(handler, invocation))
+        .map_err(|e| format!("{}. Usage: {}", e, command.usage()))
+}
+
+// The command name followed by its arguments. Words are split by shell rules,
+// so quotes group them, except that a message body is the rest of the line as
+// typed: apostrophes and runs of spaces in it are kept.
+fn split_line(line: &str) -> Result<Vec<String>, String> {
+    let Some((name, mut rest)) = next_word(line)? else {
+        return Ok(Vec::new());
+    };
+    let command = find(&name).filter(|command| command.args.last().is_some_and(|arg| arg.kind == ArgKind::Message));
+    let Some(command) = command else {
+        let mut args = vec![name];
+        args.extend(shell_words::split(rest).map_err(|e| e.to_string())?);
+        return Ok(args);
+    };
+    // 消息正文之前的参数仍按 shell 规则切分
+    let mut args = vec![name];
+    for _ in 1..command.args.len() {
+        match next_word(rest)? {
+            Some((word, remaining)) => {
+                args.push(word);
+                rest = remaining;
+            },
+            None => return Ok(args),
+        }
+    }
+    let body = rest.trim_start();
+    if !body.trim_end().is_empty() {
+        args.push(body.to_string());
+    }
+    Ok(args)
+}
+
+// `line` without the body of a message, for places where message text must
+// not be kept. None when the line does not parse.
+pub fn without_message(line: &str) -> Option<String> {
+    let args = split_line(line).ok()?;
+    match args.first().and_then(|name| find(name)) {
+        Some(command) if command.args.last().is_some_and(|arg| arg.kind == ArgKind::Message) => {
+            Some(shell_words::join(args.iter().take(command.args.len())))
+        },
+        _ => Some(line.to_string()),
+    }
+}
+
+// The first shell word of `text` and what follows it
+fn next_word(text: &str) -> Result<Option<(String, &str)>, String> {
+    let text = text.trim_start();
+    if text.is_empty() {
+        return Ok(None);
+    }
+    let (mut single, mut double, mut escaped) = (false, false, false);
+    let mut end = text.len();
+    for (i, c) in text.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if !single => escaped = true,
+            '\'' if !double => single = !single,
+            '"' if !single => double = !double,
+            c if c.is_whitespace() && !single && !double => {
+                end = i;
+                break;
+            },
+            _ => {},
+        }
+    }
+    let word = shell_words::split(&text[..end]).map_err(|e| e.to_string())?.concat();
+    Ok(Some((word, &text[end..])))
+}
+
+// What could go where the cursor is: `previous` are the words before the one
+// being completed
+pub fn complete(previous: &[&str], word: &str, peers: &[String], rooms: &[String]) -> Vec<String> {
+    let candidates: Vec<String> = match previous.split_first() {
+        None => COMMANDS.iter()
+            .flat_map(|command| std::iter::once(command.name).chain(command.aliases.iter().copied()))
+            .map(str::to_string)
+            .collect(),
+        Some((name, previous)) => match find(name) {
+            Some(command) if word.starts_with("--") => command.options.iter().map(|option| format!("--{}", option.name)).collect(),
+            Some(command) => match command.expected(previous) {
+                Some(ArgKind::Peer) => peers.to_vec(),
+                Some(ArgKind::Room) => rooms.to_vec(),
+                Some(ArgKind::Keyword(words)) => words.iter().map(|word| word.to_string()).collect(),
+                Some(ArgKind::Command) => COMMANDS.iter().map(|command| command.name.to_string()).collect(),
+                _ => Vec::new(),
+            },
+            None => Vec::new(),
+        },
+    };
+    let mut candidates: Vec<String> = candidates.into_iter().filter(|candidate| candidate.starts_with(word)).collect();
+    candidates.sort();
+    candidates.dedup();
+    candidates
+}
+
+fn print_help(command: Option<&str>) {
+    let Some(name) = command else {
+        let width = COMMANDS.iter().map(|command| command.name.len()).max().unwrap_or(0);
+        output!("Commands:");
+        for command in COMMANDS {
+            output!("  {:width$}  {}", command.name, command.summary(), width = width);
+        }
+        output!("Type 'help <command>' for details. Arguments may be quoted: update_alias bob \"Bob Smith\"");
+        return;
+    };
+    let Some(command) = find(name) else {
+        output!("Unknown command {:?}. Type 'help' to list commands.", name);
+        return;
+    };
+    output!("Usage: {}", command.usage());
+    if !command.aliases.is_empty() {
+        output!("Also: {}", command.aliases.join(", "));
+    }
+    for line in command.help.lines() {
+        output!("  {}", line.trim());
+    }
+}
+
+// Start of the local day `days_later` days after `date`, as Unix seconds
+fn local_midnight(date: &str, days_later: u64) -> Result<u64, String> {
+    let date = NaiveDate::parse_from_str(date, "%Y-%m-%d")
+        .map_err(|_| format!("Invalid date {:?}, expected YYYY-MM-DD", date))?;
+    let day = date + Days::new(days_later);
+    let midnight = day.and_hms_opt(0, 0, 0)
+        .and_then(|time| Local.from_local_datetime(&time).earliest())
+        .ok_or_else(|| format!("Invalid date {}", day))?;
+    Ok(midnight.timestamp().max(0) as u64)
+}
+
+// Turn \n, \t and \\ in a message body into the characters they name. The
+// body reaches this point exactly as typed, so this is the only escape pass.
+// Messages typed into a TUI pane go through it too.
+pub fn unescape(text: &str) -> String {
+    let mut result = String::with_capacity(text.len());
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            result.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => result.push('\n'),
+            Some('t') => result.push('\t'),
+            Some('\\') => result.push('\\'),
+            Some(other) => {
+                result.push('\\');
+                result.push(other);
+            },
+            None => result.push('\\'),
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(line: &[&str]) -> Result<Invocation, String> {
+        let words: Vec<String> = line[1..].iter().map(|word| word.to_string()).collect();
+        find(line[0]).expect("known command").parse(&words)
+    }
+
+    #[test]
+    fn finds_commands_by_name_and_alias() {
+        assert_eq!(find("send_message").unwrap().name, "send_message");
+        assert_eq!(find("msg").unwrap().name, "send_message");
+        assert!(find("nope").is_none());
+        assert!(is_exit("quit"));
+        assert!(is_exit("  exit "));
+        assert!(!is_exit("exit now"));
+        assert!(!is_exit("exiting"));
+    }
+
+    #[test]
+    fn parses_positional_arguments() {
+        let invocation = parse(&["send_message", "bob", "hello", "there"]).unwrap();
+        assert_eq!(invocation.string(0), "bob");
+        assert_eq!(invocation.rest(1), "hello there");
+
+        let invocation = parse(&["history", "bob", "#5"]).unwrap();
+        assert_eq!(invocation.number(1, 20), 5);
+        assert_eq!(parse(&["history", "bob"]).unwrap().number(1, 20), 20);
+
+        assert_eq!(parse(&["send_message", "bob"]).err().unwrap(), "Missing <message...>");
+        assert_eq!(parse(&["ping"]).err().unwrap(), "Missing <peer>");
+        assert_eq!(parse(&["ping", "a", "b"]).err().unwrap(), "Too many arguments");
+        assert!(parse(&["history", "bob", "five"]).is_err());
+        assert!(parse(&["pending", "drop"]).is_err());
+        assert!(parse(&["pending", "cancel", "3"]).is_ok());
+    }
+
+    #[test]
+    fn parses_options() {
+        let invocation = parse(&["search", "--room", "lobby", "hello", "--limit", "5", "world"]).unwrap();
+        assert_eq!(invocation.option("room"), Some("lobby"));
+        assert_eq!(invocation.option("limit"), Some("5"));
+        assert_eq!(invocation.option("from"), None);
+        assert_eq!(invocation.rest(0), "hello world");
+
+        assert_eq!(parse(&["search", "--colour", "red", "hi"]).err().unwrap(), "Unknown option --colour");
+        assert_eq!(parse(&["search", "hi", "--limit"]).err().unwrap(), "--limit needs a value");
+        assert!(parse(&["search", "--limit", "many", "hi"]).is_err());
+        assert!(parse(&["search", "--since", "2024-13-01", "hi"]).is_err());
+        assert!(parse(&["search", "--since", "2024-02-29", "hi"]).is_ok());
+        // 没有选项的命令把 -- 开头的词当作普通参数
+        assert_eq!(parse(&["send_message", "bob", "--limit"]).unwrap().rest(1), "--limit");
+    }
+
+    #[test]
+    fn local_midnight_spans_whole_days() {
+        let start = local_midnight("2024-03-01", 0).unwrap();
+        let end = local_midnight("2024-03-01", 1).unwrap();
+        assert!(end > start && end - start >= 23 * 3600 && end - start <= 25 * 3600);
+        assert!(local_midnight("yesterday", 0).is_err());
+    }
+
+    #[test]
+    fn completes_commands_and_arguments() {
+        let peers = vec!["alice".to_string(), "bob".to_string()];
+        let rooms = vec!["lobby".to_string()];
+        assert_eq!(complete(&[], "sea", &peers, &rooms), vec!["search"]);
+        assert_eq!(complete(&["msg"], "b", &peers, &rooms), vec!["bob"]);
+        assert_eq!(complete(&["search", "--room"], "", &peers, &rooms), vec!["lobby"]);
+        assert_eq!(complete(&["search"], "--l", &peers, &rooms), vec!["--limit"]);
+        assert_eq!(complete(&["pending"], "", &peers, &rooms), vec!["cancel"]);
+        assert!(complete(&["msg", "bob"], "", &peers, &rooms).is_empty());
+    }
+
+    fn split(line: &str) -> Vec<String> {
+        split_line(line).unwrap()
+    }
+
+    #[test]
+    fn keeps_message_bodies_as_typed() {
+        assert_eq!(split("send_message bob I'm late"), vec!["send_message", "bob", "I'm late"]);
+        assert_eq!(split(r"msg 'Bob Smith'   two  spaces\there "), vec!["msg", "Bob Smith", r"two  spaces\there "]);
+        assert_eq!(split(r#"say lobby "quoted" C:\\new"#), vec!["say", "lobby", r#""quoted" C:\\new"#]);
+        assert_eq!(split("broadcast it's   fine"), vec!["broadcast", "it's   fine"]);
+        assert_eq!(split("send_message bob"), vec!["send_message", "bob"]);
+        assert_eq!(split("send_message bob   "), vec!["send_message", "bob"]);
+        assert_eq!(unescape(&split(r"msg bob C:\\new\nnext")[2]), "C:\\new\nnext");
+        assert!(split_line("send_message 'bob late").is_err());
+    }
+
+    #[test]
+    fn splits_other_commands_by_shell_rules() {
+        assert!(split("   ").is_empty());
+        assert_eq!(split(r#"update_alias bob "Bob  Smith""#), vec!["update_alias", "bob", "Bob  Smith"]);
+        assert_eq!(split("search --room lobby a   b"), vec!["search", "--room", "lobby", "a", "b"]);
+        assert!(split_line("search it's").is_err());
+    }
+
+    #[test]
+    fn unescapes_message_bodies() {
+        assert_eq!(unescape(r"line one\nline two"), "line one\nline two");
+        assert_eq!(unescape(r"a\tb"), "a\tb");
+        assert_eq!(unescape(r"C:\\new"), r"C:\new");
+        assert_eq!(unescape(r"\x and \u00e9"), r"\x and \u00e9");
+        assert_eq!(unescape(r"ends with \"), r"ends with \");
+        assert_eq!(unescape("héllo 😀"), "héllo 😀");
+    }
+}
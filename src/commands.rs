@@ -1,41 +1,302 @@
 // commands.rs
-use crate::node_manager::NodeManager;
+use crate::console::output;
+use crate::node_manager::{NodeManager, RoomDelivery, SendOutcome};
+use crate::history::SearchQuery;
+use crate::network::{self, Interface};
+use crate::rooms::RoomKey;
 use std::sync::Arc;
 use tokio::sync::Mutex;
 pub struct CommandHandler {
     node_manager: Arc<Mutex<NodeManager>>,
+    interfaces: Vec<Interface>, // Interfaces chosen for discovery and chat, empty for all
 }
 
 impl CommandHandler {
-    pub fn new(node_manager:  Arc<Mutex<NodeManager>>) -> Self {
-        CommandHandler { node_manager }
+    pub fn new(node_manager:  Arc<Mutex<NodeManager>>, interfaces: Vec<Interface>) -> Self {
+        CommandHandler { node_manager, interfaces }
+    }
+
+    // List local IPv4 interfaces, marking the ones in use
+    pub fn list_interfaces(&self) {
+        match network::list_interfaces() {
+            Ok(available) => {
+                for iface in available {
+                    let used = self.interfaces.is_empty() || self.interfaces.contains(&iface);
+                    output!(
+                        "{} {} {}{}",
+                        if used { "*" } else { " " },
+                        iface.name,
+                        iface.ip,
+                        if iface.is_loopback { " (loopback)" } else { "" }
+                    );
+                }
+                output!("Set `interfaces` in the config file or P2PCHAT_INTERFACES to choose interfaces by name or address.");
+            },
+            Err(e) => output!("Failed to list interfaces: {}", e),
+        }
     }
 
     // List all users
     pub async fn list_users(&self) {
-        println!("list_users function entered.");
+        log::debug!("list_users function entered.");
         let manager = self.node_manager.lock().await; // 获取 Mutex 的锁
         let node_list = manager.list_users().await; // 调用 NodeManager 的方法
         for node in node_list {
-            println!("{}", node);
+            output!("{}", node);
         }
     }
 
     // Send a message to a user
     pub async fn send_message(&self, identifier: &str, message: &str) {
-        println!("send_message function entered.");
+        log::debug!("send_message function entered.");
+        let delivery = {
+            let manager = self.node_manager.lock().await; // 获取 Mutex 的锁
+            match manager.resolve_peer(identifier).await {
+                Ok(uuid) => manager.send_message(&uuid, message).await,
+                Err(e) => Err(e),
+            }
+        };
+        match delivery {
+            Ok(SendOutcome::Queued(id)) => {
+                output!("{} is offline, message queued as #{} until it comes back (see `pending`)", identifier, id)
+            },
+            Ok(SendOutcome::QueuedBehind(id)) => {
+                output!("Earlier messages to {} are still queued, message queued behind them as #{} (see `pending`)", identifier, id)
+            },
+            // 投递结果以 Delivered / DeliveryFailed 事件报告
+            Ok(SendOutcome::Sending(_)) => {},
+            Err(e) => output!("Failed to send message: {}", e),
+        }
+    }
+
+    // Send a message to everyone online
+    pub async fn broadcast(&self, message: &str) {
+        let delivery = {
+            let manager = self.node_manager.lock().await; // 获取 Mutex 的锁
+            manager.broadcast(message).await
+        };
+        match delivery {
+            // 每个节点的投递结果以事件报告
+            Ok(handles) => output!("Broadcast sent to {} peer(s)", handles.len()),
+            Err(e) => output!("Failed to broadcast: {}", e),
+        }
+    }
+
+    pub async fn join_room(&self, room: &str, passphrase: Option<&str>) {
+        // 在获取锁之前派生房间密钥，避免阻塞消息处理
+        let key = match passphrase {
+            Some(passphrase) => match RoomKey::derive_in_background(room, passphrase).await {
+                Ok(key) => Some(key),
+                Err(e) => {
+                    output!("Failed to join room {}: {}", room, e);
+                    return;
+                }
+            },
+            None => None,
+        };
+        let manager = self.node_manager.lock().await; // 获取 Mutex 的锁
+        match manager.join_room(room, key).await {
+            Ok(true) => output!("Joined room {}", room),
+            Ok(false) => output!("Already in room {}", room),
+            Err(e) => output!("Failed to join room {}: {}", room, e),
+        }
+    }
+
+    pub async fn leave_room(&self, room: &str) {
+        let manager = self.node_manager.lock().await; // 获取 Mutex 的锁
+        match manager.leave_room(room).await {
+            Ok(true) => output!("Left room {}", room),
+            Ok(false) => output!("Not in room {}", room),
+            Err(e) => output!("Failed to leave room {}: {}", room, e),
+        }
+    }
+
+    // List known rooms, marking the joined ones with "*"
+    pub async fn list_rooms(&self) {
+        let manager = self.node_manager.lock().await; // 获取 Mutex 的锁
+        let rooms = manager.list_rooms().await;
+        if rooms.is_empty() {
+            output!("No rooms yet, create one with `join <room>`");
+        }
+        for room in rooms {
+            output!("{}", room);
+        }
+    }
+
+    // Send a message to the members of a room
+    pub async fn say(&self, room: &str, message: &str) {
+        let delivery = {
+            let manager = self.node_manager.lock().await; // 获取 Mutex 的锁
+            manager.say(room, message).await
+        };
+        match delivery {
+            Ok(RoomDelivery::Peers(handles)) if handles.is_empty() => output!("No other members of room {} are online", room),
+            Ok(RoomDelivery::Peers(handles)) => output!("Message to room {} sent to {} peer(s)", room, handles.len()),
+            Ok(RoomDelivery::Multicast) => output!("Message sent to private room {}", room),
+            Err(e) => output!("Failed to send to room {}: {}", room, e),
+        }
+    }
+
+    pub async fn room_history(&self, room: &str, count: usize) {
+        let manager = self.node_manager.lock().await; // 获取 Mutex 的锁
+        match manager.room_history(room, count).await {
+            Ok(lines) => {
+                for line in lines {
+                    output!("{}", line);
+                }
+            },
+            Err(e) => output!("Failed to show history of room {}: {}", room, e),
+        }
+    }
+
+    // Show stored messages exchanged with a user
+    pub async fn history(&self, identifier: &str, count: usize) {
+        let manager = self.node_manager.lock().await; // 获取 Mutex 的锁
+        let result = match manager.resolve_peer(identifier).await {
+            Ok(uuid) => manager.history(&uuid, count).await,
+            Err(e) => Err(e),
+        };
+        match result {
+            Ok(lines) if lines.is_empty() => output!("No stored messages with {}", identifier),
+            Ok(lines) => {
+                for line in lines {
+                    output!("{}", line);
+                }
+            },
+            Err(e) => output!("Failed to show history with {}: {}", identifier, e),
+        }
+    }
+
+    // Search stored messages; `from` names the sender, "me" for our own messages
+    pub async fn search(&self, mut query: SearchQuery, from: Option<&str>) {
+        let manager = self.node_manager.lock().await; // 获取 Mutex 的锁
+        query.sender = match from {
+            Some("me") => Some(manager.uuid.clone()),
+            Some(from) => match manager.resolve_peer(from).await {
+                Ok(uuid) => Some(uuid),
+                Err(e) => {
+                    output!("Failed to search: {}", e);
+                    return;
+                }
+            },
+            None => None,
+        };
+        match manager.search(&query).await {
+            Ok(lines) if lines.is_empty() => output!("No messages match {:?}", query.text),
+            Ok(lines) => {
+                for line in lines {
+                    output!("{}", line);
+                }
+            },
+            Err(e) => output!("Failed to search: {}", e),
+        }
+    }
+
+    // List messages waiting for offline users
+    pub async fn list_pending(&self) {
+        let manager = self.node_manager.lock().await; // 获取 Mutex 的锁
+        let pending = manager.list_pending().await;
+        if pending.is_empty() {
+            output!("No queued messages");
+        }
+        for line in pending {
+            output!("{}", line);
+        }
+    }
+
+    pub async fn cancel_pending(&self, id: u64) {
+        let manager = self.node_manager.lock().await; // 获取 Mutex 的锁
+        match manager.cancel_pending(id).await {
+            Ok(_) => output!("Cancelled queued message #{}", id),
+            Err(e) => output!("Failed to cancel: {}", e),
+        }
+    }
+
+    // Check that a user is reachable
+    pub async fn ping(&self, identifier: &str) {
+        let manager = self.node_manager.lock().await; // 获取 Mutex 的锁
+        let result = match manager.resolve_peer(identifier).await {
+            Ok(uuid) => manager.ping(&uuid).await,
+            Err(e) => Err(e),
+        };
+        if let Err(e) = result {
+            output!("Failed to ping {}: {}", identifier, e);
+        }
+    }
+
+    // Show what is known about a user
+    pub async fn info(&self, identifier: &str) {
+        let manager = self.node_manager.lock().await; // 获取 Mutex 的锁
+        let result = match manager.resolve_peer(identifier).await {
+            Ok(uuid) => manager.peer_info(&uuid).await,
+            Err(e) => Err(e),
+        };
+        match result {
+            Ok(lines) => {
+                for line in lines {
+                    output!("{}", line);
+                }
+            },
+            Err(e) => output!("Failed to show info for {}: {}", identifier, e),
+        }
+    }
+
+    // Drop a user from the online list until it announces itself again
+    pub async fn remove(&self, identifier: &str) {
+        let manager = self.node_manager.lock().await; // 获取 Mutex 的锁
+        match manager.resolve_peer(identifier).await {
+            Ok(uuid) if manager.remove_node(&uuid).await.is_some() => output!("Removed {}", uuid),
+            Ok(uuid) => output!("Failed to remove {}: not online", uuid),
+            Err(e) => output!("Failed to remove {}: {}", identifier, e),
+        }
+    }
+
+    // Show our fingerprint, or a peer's recorded fingerprint
+    pub async fn fingerprint(&self, identifier: Option<&str>) {
+        let manager = self.node_manager.lock().await; // 获取 Mutex 的锁
+        let uuid = match identifier {
+            Some(identifier) => match manager.resolve_peer(identifier).await {
+                Ok(uuid) => Some(uuid),
+                Err(e) => {
+                    output!("Failed to show fingerprint: {}", e);
+                    return;
+                }
+            },
+            None => None,
+        };
+        match (uuid.as_deref(), manager.fingerprint(uuid.as_deref()).await) {
+            (None, Ok((fingerprint, _))) => output!("Your fingerprint: {}", fingerprint),
+            (Some(uuid), Ok((fingerprint, verified))) => output!(
+                "Fingerprint of {}: {} ({})",
+                uuid, fingerprint, if verified { "verified" } else { "unverified" }
+            ),
+            (_, Err(e)) => output!("Failed to show fingerprint: {}", e),
+        }
+    }
+
+    // Mark a peer's key as verified after comparing fingerprints out of band
+    pub async fn verify(&self, identifier: &str) {
         let manager = self.node_manager.lock().await; // 获取 Mutex 的锁
-        if let Err(e) = manager.send_message(identifier, message).await {
-            println!("Failed to send message: {}", e);
+        let result = match manager.resolve_peer(identifier).await {
+            Ok(uuid) => manager.verify_peer(&uuid).await.map(|_| uuid),
+            Err(e) => Err(e),
+        };
+        match result {
+            Ok(uuid) => output!("Key of {} marked as verified", uuid),
+            Err(e) => output!("Failed to verify {}: {}", identifier, e),
         }
     }
 
     // Update a user's alias
-    pub async fn update_alias(&self, uuid: &str, alias: &str) {
+    pub async fn update_alias(&self, identifier: &str, alias: &str) {
         let manager = self.node_manager.lock().await; // 获取 Mutex 的锁
-        match manager.update_node_alias(uuid, alias.to_string()).await {
-            Ok(_) => println!("Alias updated for UUID {}: {}", uuid, alias),
-            Err(e) => println!("Failed to update alias for UUID {}: {}", uuid, e),
+        let result = match manager.resolve_peer(identifier).await {
+            Ok(uuid) => manager.update_node_alias(&uuid, alias.to_string()).await.map(|_| uuid),
+            Err(e) => Err(e),
+        };
+        match result {
+            Ok(uuid) => output!("Alias updated for UUID {}: {}", uuid, alias),
+            Err(e) => output!("Failed to update alias for {}: {}", identifier, e),
         }
     }
 }
@@ -0,0 +1,218 @@
+// config.rs
+use serde::Deserialize;
+use std::env;
+use std::fs;
+use std::io;
+use std::net::Ipv4Addr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use tokio::time::Duration;
+use crate::fragment::DEFAULT_MAX_MESSAGE_SIZE;
+use crate::protocol::{MAX_DISPLAY_NAME_LEN, MAX_STATUS_LEN};
+
+pub const DEFAULT_CONFIG_FILE: &str = "config.toml";
+// Environment variable pointing at a config file other than the default
+pub const CONFIG_ENV: &str = "P2PCHAT_CONFIG";
+
+const SECONDS_PER_DAY: u64 = 24 * 3600;
+
+// Settings read from the config file; every field can be left out.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    // Name shown to other users
+    pub display_name: Option<String>,
+    // Short status text shown next to the name, e.g. "in a meeting"
+    pub status: Option<String>,
+    // Local address for the chat socket; chosen from `interfaces` when unset
+    pub bind_addr: Option<Ipv4Addr>,
+    // Port for the chat socket, 0 for any free port
+    pub port: u16,
+    // Multicast group used for presence announcements
+    pub multicast_addr: String,
+    // Interface names or IPv4 addresses to use; empty means all
+    pub interfaces: Vec<String>,
+    pub announce_interval_secs: u64,
+    pub check_interval_secs: u64,
+    // A peer not heard from for this long is considered offline
+    pub offline_timeout_secs: u64,
+    // Largest chat message accepted or sent, in bytes
+    pub max_message_size: usize,
+    pub profile_dir: PathBuf,
+    pub log_config: PathBuf,
+    // Keep sent and received messages in the profile directory; off by default
+    pub history: bool,
+    // Stored messages older than this many days are deleted, 0 keeps them forever
+    pub history_retention_days: u64,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            display_name: None,
+            status: None,
+            bind_addr: None,
+            port: 0,
+            multicast_addr: "239.255.255.250:3000".to_string(),
+            interfaces: Vec::new(),
+            announce_interval_secs: 5,
+            check_interval_secs: 10,
+            offline_timeout_secs: 20,
+            max_message_size: DEFAULT_MAX_MESSAGE_SIZE,
+            profile_dir: PathBuf::from("profile"),
+            log_config: PathBuf::from("log4rs.yaml"),
+            history: false,
+            history_retention_days: 30,
+        }
+    }
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, message)
+}
+
+fn env_value<T: FromStr>(name: &str) -> io::Result<Option<T>> {
+    match env::var(name) {
+        Ok(value) => value.trim().parse().map(Some)
+            .map_err(|_| invalid(format!("{} has an invalid value: {}", name, value))),
+        Err(_) => Ok(None),
+    }
+}
+
+fn check_text(name: &str, text: Option<&str>, max: usize) -> io::Result<()> {
+    let Some(text) = text else {
+        return Ok(());
+    };
+    if text.trim().is_empty() || text.chars().any(char::is_control) {
+        return Err(invalid(format!("{} must be printable text", name)));
+    }
+    if text.chars().count() > max {
+        return Err(invalid(format!("{} is longer than {} characters", name, max)));
+    }
+    Ok(())
+}
+
+impl Config {
+    // Read the config file at `path`, which must exist
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let text = fs::read_to_string(path)
+            .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path.display(), e)))?;
+        toml::from_str(&text).map_err(|e| invalid(format!("{}: {}", path.display(), e)))
+    }
+
+    // Config file from `path`, P2PCHAT_CONFIG or the default path, then
+    // environment overrides. Command-line overrides are applied by the caller.
+    // Only a missing default file means the defaults; a file asked for by name
+    // must exist, so a mistyped path is not silently ignored.
+    pub fn from_file_and_env(path: Option<&Path>) -> io::Result<Self> {
+        let explicit = path.map(Path::to_path_buf).or_else(|| env::var_os(CONFIG_ENV).map(PathBuf::from));
+        let mut config = match explicit {
+            Some(path) => Config::load(&path)?,
+            None => match Config::load(Path::new(DEFAULT_CONFIG_FILE)) {
+                Err(e) if e.kind() == io::ErrorKind::NotFound => Config::default(),
+                result => result?,
+            },
+        };
+        config.apply_env()?;
+        Ok(config)
+    }
+
+    // 环境变量优先于配置文件
+    pub fn apply_env(&mut self) -> io::Result<()> {
+        if let Some(value) = env_value("P2PCHAT_NAME")? {
+            self.display_name = Some(value);
+        }
+        if let Some(value) = env_value("P2PCHAT_STATUS")? {
+            self.status = Some(value);
+        }
+        if let Some(value) = env_value("P2PCHAT_BIND")? {
+            self.bind_addr = Some(value);
+        }
+        if let Some(value) = env_value("P2PCHAT_PORT")? {
+            self.port = value;
+        }
+        if let Some(value) = env_value("P2PCHAT_MULTICAST_ADDR")? {
+            self.multicast_addr = value;
+        }
+        if let Ok(value) = env::var("P2PCHAT_INTERFACES") {
+            self.interfaces = value.split(',').map(str::trim).filter(|s| !s.is_empty()).map(String::from).collect();
+        }
+        if let Some(value) = env_value("P2PCHAT_ANNOUNCE_INTERVAL")? {
+            self.announce_interval_secs = value;
+        }
+        if let Some(value) = env_value("P2PCHAT_CHECK_INTERVAL")? {
+            self.check_interval_secs = value;
+        }
+        if let Some(value) = env_value("P2PCHAT_OFFLINE_TIMEOUT")? {
+            self.offline_timeout_secs = value;
+        }
+        if let Some(value) = env_value("P2PCHAT_MAX_MESSAGE_SIZE")? {
+            self.max_message_size = value;
+        }
+        if let Some(value) = env_value::<String>("P2PCHAT_PROFILE_DIR")? {
+            self.profile_dir = PathBuf::from(value);
+        }
+        if let Some(value) = env_value::<String>("P2PCHAT_LOG_CONFIG")? {
+            self.log_config = PathBuf::from(value);
+        }
+        if let Some(value) = env_value("P2PCHAT_HISTORY")? {
+            self.history = value;
+        }
+        if let Some(value) = env_value("P2PCHAT_HISTORY_RETENTION_DAYS")? {
+            self.history_retention_days = value;
+        }
+        Ok(())
+    }
+
+    pub fn validate(&self) -> io::Result<()> {
+        check_text("display_name", self.display_name.as_deref(), MAX_DISPLAY_NAME_LEN)?;
+        check_text("status", self.status.as_deref(), MAX_STATUS_LEN)?;
+        self.multicast_addr.parse::<std::net::SocketAddrV4>()
+            .map_err(|e| invalid(format!("multicast_addr {}: {}", self.multicast_addr, e)))?;
+        if self.announce_interval_secs == 0 || self.check_interval_secs == 0 {
+            return Err(invalid("intervals must be at least one second".to_string()));
+        }
+        if self.offline_timeout_secs <= self.announce_interval_secs {
+            return Err(invalid("offline_timeout_secs must be longer than announce_interval_secs".to_string()));
+        }
+        if self.history_retention_days.checked_mul(SECONDS_PER_DAY).is_none() {
+            return Err(invalid(format!("history_retention_days {} is too large", self.history_retention_days)));
+        }
+        Ok(())
+    }
+
+    pub fn announce_interval(&self) -> Duration {
+        Duration::from_secs(self.announce_interval_secs)
+    }
+
+    pub fn check_interval(&self) -> Duration {
+        Duration::from_secs(self.check_interval_secs)
+    }
+
+    pub fn offline_timeout(&self) -> Duration {
+        Duration::from_secs(self.offline_timeout_secs)
+    }
+
+    pub fn history_retention(&self) -> Option<Duration> {
+        (self.history_retention_days > 0).then(|| Duration::from_secs(self.history_retention_days.saturating_mul(SECONDS_PER_DAY)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::profile::TestDir;
+
+    #[test]
+    fn a_named_config_file_must_exist() {
+        let dir = TestDir::new();
+        let missing = dir.join("conifg.toml");
+        let error = Config::from_file_and_env(Some(&missing)).err().unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::NotFound);
+        assert!(error.to_string().contains("conifg.toml"));
+
+        let path = dir.join("config.toml");
+        fs::write(&path, "port = 4000\n").unwrap();
+        assert_eq!(Config::load(&path).unwrap().port, 4000);
+    }
+}
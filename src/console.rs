@@ -0,0 +1,29 @@
+// console.rs
+use std::sync::OnceLock;
+use tokio::sync::mpsc;
+
+// Command output goes to stdout until a frontend takes it over
+static SINK: OnceLock<mpsc::UnboundedSender<String>> = OnceLock::new();
+
+pub fn redirect(sink: mpsc::UnboundedSender<String>) {
+    if SINK.set(sink).is_err() {
+        log::warn!("Console output is already redirected");
+    }
+}
+
+pub fn write_line(line: String) {
+    match SINK.get() {
+        Some(sink) => {
+            let _ = sink.send(line);
+        },
+        None => println!("{}", line),
+    }
+}
+
+// println! for everything the user should see while the node runs
+macro_rules! output {
+    ($($arg:tt)*) => {
+        $crate::console::write_line(format!($($arg)*))
+    };
+}
+pub(crate) use output;
@@ -0,0 +1,173 @@
+// delivery.rs
+use std::collections::HashMap;
+use std::net::Ipv4Addr;
+use std::sync::Arc;
+use tokio::sync::{oneshot, Mutex};
+use tokio::time::{self, Duration, Instant};
+use log::{info, warn};
+use crate::protocol::MAX_CLOCK_SKEW;
+use crate::udp_connection::ChatSockets;
+
+// 重传参数：首次等待 500ms，之后每次翻倍
+pub const MAX_ATTEMPTS: u32 = 5;
+pub const INITIAL_TIMEOUT: Duration = Duration::from_millis(500);
+// How long a received message id is remembered: long enough to answer
+// retransmissions and to outlast the window in which a room message's signed
+// timestamp is accepted. Direct messages are also checked by their session.
+const SEEN_TTL: Duration = Duration::from_secs(2 * MAX_CLOCK_SKEW);
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum DeliveryStatus {
+    Delivered { attempts: u32 },
+    Failed { attempts: u32 },
+    // The recipient could not decrypt the message because it has no matching session
+    SessionLost,
+    // The message was never sent, e.g. because no encrypted session could be set up
+    Aborted { reason: String },
+}
+
+// What the recipient told us about a message
+enum Receipt {
+    Acked,
+    SessionUnknown,
+}
+
+// Tracks messages waiting for an Ack and message ids we have already received
+#[derive(Clone, Default)]
+pub struct AckTracker {
+    pending: Arc<Mutex<HashMap<String, oneshot::Sender<Receipt>>>>,
+    seen: Arc<Mutex<HashMap<String, Instant>>>,
+}
+
+impl AckTracker {
+    pub fn new() -> Self {
+        AckTracker::default()
+    }
+
+    // Called when an Ack arrives; returns false for unknown or late acks
+    pub async fn acknowledge(&self, id: &str) -> bool {
+        self.resolve(id, Receipt::Acked).await
+    }
+
+    // Called when the recipient reports it cannot decrypt the message
+    pub async fn session_unknown(&self, id: &str) -> bool {
+        self.resolve(id, Receipt::SessionUnknown).await
+    }
+
+    async fn resolve(&self, id: &str, receipt: Receipt) -> bool {
+        match self.pending.lock().await.remove(id) {
+            Some(done) => done.send(receipt).is_ok(),
+            None => false,
+        }
+    }
+
+    // Returns true the first time a message id is seen
+    pub async fn first_sighting(&self, id: &str) -> bool {
+        let now = Instant::now();
+        let mut seen = self.seen.lock().await;
+        seen.retain(|_, at| now.duration_since(*at) < SEEN_TTL);
+        seen.insert(id.to_string(), now).is_none()
+    }
+
+    pub async fn has_seen(&self, id: &str) -> bool {
+        self.seen.lock().await.contains_key(id)
+    }
+
+    // Send every datagram of a message until the peer acknowledges `id` or the attempts run out
+    pub async fn deliver(&self, sockets: &ChatSockets, id: &str, ip: Ipv4Addr, port: u16, datagrams: &[Vec<u8>]) -> DeliveryStatus {
+        let (done_tx, mut done_rx) = oneshot::channel();
+        self.pending.lock().await.insert(id.to_string(), done_tx);
+
+        let mut wait = INITIAL_TIMEOUT;
+        for attempt in 1..=MAX_ATTEMPTS {
+            for data in datagrams {
+                if let Err(e) = sockets.send_message(ip, port, data).await {
+                    warn!("Attempt {} for message {} failed: {}", attempt, id, e);
+                }
+            }
+            match time::timeout(wait, &mut done_rx).await {
+                Ok(Ok(Receipt::Acked)) => {
+                    info!("Message {} delivered after {} attempt(s)", id, attempt);
+                    return DeliveryStatus::Delivered { attempts: attempt };
+                },
+                Ok(Ok(Receipt::SessionUnknown)) => return DeliveryStatus::SessionLost,
+                Ok(Err(_)) => break,
+                Err(_) => wait *= 2,
+            }
+        }
+        self.pending.lock().await.remove(id);
+        DeliveryStatus::Failed { attempts: MAX_ATTEMPTS }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::UdpSocket;
+
+    // A chat socket to send from and a peer socket that receives
+    async fn sockets() -> (ChatSockets, UdpSocket, u16) {
+        let local = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let peer = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let port = peer.local_addr().unwrap().port();
+        (ChatSockets::new(vec![(local, None)]), peer, port)
+    }
+
+    #[tokio::test]
+    async fn retransmits_until_acknowledged() {
+        let (chat, peer, port) = sockets().await;
+        let tracker = AckTracker::new();
+        let acker = tracker.clone();
+        // 第一次发送丢失，收到重传后才回复 Ack
+        let peer_task = tokio::spawn(async move {
+            let mut buf = [0u8; 64];
+            for _ in 0..2 {
+                peer.recv_from(&mut buf).await.unwrap();
+            }
+            assert!(acker.acknowledge("m1").await);
+        });
+        let status = tracker.deliver(&chat, "m1", Ipv4Addr::LOCALHOST, port, &[b"one".to_vec()]).await;
+        assert_eq!(status, DeliveryStatus::Delivered { attempts: 2 });
+        peer_task.await.unwrap();
+        assert!(!tracker.acknowledge("m1").await);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn gives_up_after_the_last_attempt() {
+        let (chat, peer, port) = sockets().await;
+        let tracker = AckTracker::new();
+        let status = tracker.deliver(&chat, "m1", Ipv4Addr::LOCALHOST, port, &[b"one".to_vec(), b"two".to_vec()]).await;
+        assert_eq!(status, DeliveryStatus::Failed { attempts: MAX_ATTEMPTS });
+        let mut buf = [0u8; 64];
+        let mut received = 0;
+        while peer.try_recv_from(&mut buf).is_ok() {
+            received += 1;
+        }
+        assert_eq!(received, 2 * MAX_ATTEMPTS);
+        // 放弃之后到达的 Ack 被忽略
+        assert!(!tracker.acknowledge("m1").await);
+    }
+
+    #[tokio::test]
+    async fn stops_when_the_recipient_has_no_session() {
+        let (chat, _peer, port) = sockets().await;
+        let tracker = AckTracker::new();
+        let reporter = tracker.clone();
+        tokio::spawn(async move {
+            while !reporter.session_unknown("m1").await {
+                tokio::task::yield_now().await;
+            }
+        });
+        let status = tracker.deliver(&chat, "m1", Ipv4Addr::LOCALHOST, port, &[b"one".to_vec()]).await;
+        assert_eq!(status, DeliveryStatus::SessionLost);
+    }
+
+    #[tokio::test]
+    async fn ids_are_seen_once() {
+        let tracker = AckTracker::new();
+        assert!(!tracker.has_seen("m1").await);
+        assert!(tracker.first_sighting("m1").await);
+        assert!(!tracker.first_sighting("m1").await);
+        assert!(tracker.has_seen("m1").await);
+    }
+}
@@ -0,0 +1,125 @@
+// events.rs
+use std::fmt;
+use std::net::Ipv4Addr;
+use tokio::sync::broadcast;
+
+// Events a slow subscriber may fall behind by before it starts missing some
+const EVENT_BUFFER: usize = 256;
+
+// Which conversation a received message belongs to
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Conversation {
+    Peer(String), // UUID
+    Broadcast,
+    Room(String),
+}
+
+// Things that happen without the user asking, published by NodeManager to
+// every frontend that subscribed
+#[derive(Debug, Clone)]
+pub enum Event {
+    PeerOnline {
+        uuid: String,
+        display_name: Option<String>,
+    },
+    PeerOffline {
+        uuid: String,
+        display_name: Option<String>,
+    },
+    // An announcement was refused, e.g. because the peer's key changed or its
+    // clock is too far from ours
+    PeerRejected {
+        reason: String,
+    },
+    MessageReceived {
+        conversation: Conversation,
+        sender: String, // UUID
+        ip: Ipv4Addr,
+        port: u16,
+        text: String, // Without control characters other than \n and \t
+        // Came sealed with a room passphrase over the multicast group
+        private: bool,
+    },
+    Pong {
+        uuid: String,
+    },
+    // A direct, broadcast or open room message was acknowledged by `recipient`.
+    // `pending_id` is set for a message that waited in the queue. Private room
+    // messages go to the multicast group unacknowledged and report neither this
+    // nor DeliveryFailed.
+    Delivered {
+        recipient: String, // UUID
+        conversation: Conversation,
+        pending_id: Option<u64>,
+        attempts: u32,
+    },
+    // A message did not reach `recipient`; `requeued` tells whether a queued
+    // message stays in the queue to be tried again
+    DeliveryFailed {
+        recipient: String, // UUID
+        conversation: Conversation,
+        pending_id: Option<u64>,
+        reason: String,
+        requeued: bool,
+    },
+}
+
+// How a sent message is named in delivery notifications
+fn sent_label(conversation: &Conversation) -> String {
+    match conversation {
+        Conversation::Peer(_) => "Message".to_string(),
+        Conversation::Broadcast => "Broadcast".to_string(),
+        Conversation::Room(room) => format!("Message to room {}", room),
+    }
+}
+
+fn peer_label(uuid: &str, display_name: &Option<String>) -> String {
+    match display_name {
+        Some(name) => format!("{} ({})", uuid, name),
+        None => uuid.to_string(),
+    }
+}
+
+// The line the plain terminal prints for an event
+impl fmt::Display for Event {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Event::PeerOnline { uuid, display_name } => {
+                write!(f, "Notification: Node {} came online!", peer_label(uuid, display_name))
+            },
+            Event::PeerOffline { uuid, display_name } => {
+                write!(f, "Notification: Node {} went offline!", peer_label(uuid, display_name))
+            },
+            Event::PeerRejected { reason } => write!(f, "Notification: WARNING: {}", reason),
+            Event::MessageReceived { conversation, sender, ip, port, text, private } => {
+                let kind = match (conversation, private) {
+                    (Conversation::Peer(_), _) => "Message".to_string(),
+                    (Conversation::Broadcast, _) => "Broadcast".to_string(),
+                    (Conversation::Room(room), false) => format!("Room Message [{}]", room),
+                    (Conversation::Room(room), true) => format!("Private Room Message [{}]", room),
+                };
+                write!(f, "Received {}: IP = {}, Port = {}, UUID = {}, Content = {}", kind, ip, port, sender, text)
+            },
+            Event::Pong { uuid } => write!(f, "Pong from UUID: {}", uuid),
+            Event::Delivered { recipient, pending_id: Some(id), .. } => {
+                write!(f, "Queued message #{} delivered to {}", id, recipient)
+            },
+            Event::Delivered { recipient, conversation, pending_id: None, attempts } => {
+                write!(f, "{} delivered to {} ({} attempt(s))", sent_label(conversation), recipient, attempts)
+            },
+            Event::DeliveryFailed { recipient, pending_id: Some(id), reason, requeued: true, .. } => {
+                write!(f, "Queued message #{} to {} was not delivered ({}), keeping it queued", id, recipient, reason)
+            },
+            Event::DeliveryFailed { recipient, pending_id: Some(id), reason, requeued: false, .. } => {
+                write!(f, "Dropping queued message #{} to {}: {}", id, recipient, reason)
+            },
+            Event::DeliveryFailed { recipient, conversation, pending_id: None, reason, .. } => {
+                write!(f, "{} was not delivered to {}: {}", sent_label(conversation), recipient, reason)
+            },
+        }
+    }
+}
+
+pub fn channel() -> broadcast::Sender<Event> {
+    broadcast::channel(EVENT_BUFFER).0
+}
@@ -0,0 +1,251 @@
+// fragment.rs
+use std::collections::HashMap;
+use std::fmt;
+use tokio::time::{Duration, Instant};
+
+// 每个分片携带的内容上限（按 JSON 转义后的字节数计算），保证单个数据报不超过 1024 字节
+pub const FRAGMENT_CONTENT_SIZE: usize = 700;
+pub const DEFAULT_MAX_MESSAGE_SIZE: usize = 64 * 1024;
+// Incomplete fragment sets are discarded after this long
+pub const REASSEMBLY_TIMEOUT: Duration = Duration::from_secs(30);
+// Incomplete messages one sender may have open at a time
+pub const MAX_PARTIAL_PER_SENDER: usize = 16;
+
+// Bytes a character occupies once escaped inside a JSON string
+fn json_len(c: char) -> usize {
+    match c {
+        '"' | '\\' | '\n' | '\r' | '\t' | '\u{08}' | '\u{0c}' => 2,
+        c if (c as u32) < 0x20 => 6,
+        c => c.len_utf8(),
+    }
+}
+
+// Split `content` on character boundaries into chunks that each fit in one fragment
+pub fn split(content: &str, chunk_size: usize) -> Vec<String> {
+    let mut chunks = Vec::new();
+    let mut current = String::new();
+    let mut current_len = 0;
+    for c in content.chars() {
+        let len = json_len(c);
+        if current_len + len > chunk_size && !current.is_empty() {
+            chunks.push(std::mem::take(&mut current));
+            current_len = 0;
+        }
+        current.push(c);
+        current_len += len;
+    }
+    if !current.is_empty() || chunks.is_empty() {
+        chunks.push(current);
+    }
+    chunks
+}
+
+struct Partial {
+    sender: String,
+    parts: Vec<Option<String>>,
+    size: usize,
+    started: Instant,
+}
+
+#[derive(Debug)]
+pub enum FragmentError {
+    TooLarge { id: String },
+    Inconsistent { id: String },
+    TooManyOpen { id: String },
+}
+
+impl fmt::Display for FragmentError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FragmentError::TooLarge { id } => write!(f, "message {} exceeds the maximum message size", id),
+            FragmentError::Inconsistent { id } => write!(f, "fragments of message {} do not match", id),
+            FragmentError::TooManyOpen { id } => write!(f, "too many incomplete messages from the sender of {}", id),
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct Reassembler {
+    partial: HashMap<String, Partial>,
+}
+
+impl Reassembler {
+    pub fn new() -> Self {
+        Reassembler::default()
+    }
+
+    // Store one fragment; returns the full content once every fragment has arrived.
+    // `max_size` is the longest content a complete message may have.
+    pub fn insert(
+        &mut self,
+        sender: &str,
+        id: &str,
+        index: u16,
+        total: u16,
+        content: String,
+        max_size: usize,
+    ) -> Result<Option<String>, FragmentError> {
+        if total == 0 || index >= total {
+            return Err(FragmentError::Inconsistent { id: id.to_string() });
+        }
+        // 先检查声明的分片数，避免为伪造的 total 预先分配大量空间
+        if total as usize > max_size.div_ceil(FRAGMENT_CONTENT_SIZE) {
+            return Err(FragmentError::TooLarge { id: id.to_string() });
+        }
+        if !self.partial.contains_key(id)
+            && self.partial.values().filter(|partial| partial.sender == sender).count() >= MAX_PARTIAL_PER_SENDER
+        {
+            return Err(FragmentError::TooManyOpen { id: id.to_string() });
+        }
+        let partial = self.partial.entry(id.to_string()).or_insert_with(|| Partial {
+            sender: sender.to_string(),
+            parts: vec![None; total as usize],
+            size: 0,
+            started: Instant::now(),
+        });
+        if partial.sender != sender || partial.parts.len() != total as usize {
+            return Err(FragmentError::Inconsistent { id: id.to_string() });
+        }
+
+        let slot = &mut partial.parts[index as usize];
+        if slot.is_none() {
+            partial.size += content.len();
+            *slot = Some(content);
+        }
+        // 超过最大消息长度时丢弃整组分片
+        if partial.size > max_size {
+            self.partial.remove(id);
+            return Err(FragmentError::TooLarge { id: id.to_string() });
+        }
+        if partial.parts.iter().any(Option::is_none) {
+            return Ok(None);
+        }
+
+        let partial = self.partial.remove(id).expect("partial message present");
+        Ok(Some(partial.parts.into_iter().flatten().collect()))
+    }
+
+    // Drop fragment sets that did not complete in time, returning (id, sender) for each
+    pub fn prune(&mut self, timeout: Duration) -> Vec<(String, String)> {
+        let now = Instant::now();
+        let mut expired = Vec::new();
+        self.partial.retain(|id, partial| {
+            if now.duration_since(partial.started) > timeout {
+                expired.push((id.clone(), partial.sender.clone()));
+                false
+            } else {
+                true
+            }
+        });
+        expired
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MAX: usize = DEFAULT_MAX_MESSAGE_SIZE;
+
+    fn encoded_len(chunk: &str) -> usize {
+        serde_json::to_string(chunk).unwrap().len() - 2
+    }
+
+    #[test]
+    fn split_keeps_escaped_chunks_within_the_limit() {
+        let content = "é\"漢\\字\n😀\t\u{1}a".repeat(50);
+        let chunks = split(&content, 16);
+        assert!(chunks.len() > 1);
+        for chunk in &chunks {
+            assert!(!chunk.is_empty());
+            assert!(encoded_len(chunk) <= 16, "{:?} is {} bytes escaped", chunk, encoded_len(chunk));
+        }
+        assert_eq!(chunks.concat(), content);
+    }
+
+    #[test]
+    fn split_empty_content_gives_one_chunk() {
+        assert_eq!(split("", FRAGMENT_CONTENT_SIZE), vec![String::new()]);
+    }
+
+    #[test]
+    fn reassembles_out_of_order_fragments() {
+        let content = "ünïcödé \"quoted\" \\ line\nbreak 😀 ".repeat(40);
+        let chunks = split(&content, FRAGMENT_CONTENT_SIZE);
+        let total = chunks.len() as u16;
+        assert!(total > 1);
+
+        let mut reassembler = Reassembler::new();
+        let mut result = None;
+        for (index, chunk) in chunks.iter().enumerate().rev() {
+            assert!(result.is_none(), "completed before the last fragment");
+            result = reassembler.insert("alice", "m1", index as u16, total, chunk.clone(), MAX).unwrap();
+        }
+        assert_eq!(result.as_deref(), Some(content.as_str()));
+        assert!(reassembler.partial.is_empty());
+    }
+
+    #[test]
+    fn repeated_fragment_is_counted_once() {
+        let first = "a".repeat(FRAGMENT_CONTENT_SIZE);
+        let max = FRAGMENT_CONTENT_SIZE + 1;
+        let mut reassembler = Reassembler::new();
+        assert!(reassembler.insert("alice", "m1", 0, 2, first.clone(), max).unwrap().is_none());
+        assert!(reassembler.insert("alice", "m1", 0, 2, first.clone(), max).unwrap().is_none());
+        assert_eq!(reassembler.insert("alice", "m1", 1, 2, "b".into(), max).unwrap(), Some(first + "b"));
+    }
+
+    #[test]
+    fn rejects_inconsistent_fragments() {
+        let mut reassembler = Reassembler::new();
+        assert!(matches!(reassembler.insert("alice", "m1", 0, 0, "a".into(), MAX), Err(FragmentError::Inconsistent { .. })));
+        assert!(matches!(reassembler.insert("alice", "m1", 2, 2, "a".into(), MAX), Err(FragmentError::Inconsistent { .. })));
+
+        reassembler.insert("alice", "m1", 0, 3, "a".into(), MAX).unwrap();
+        // total 与第一个分片不一致
+        assert!(matches!(reassembler.insert("alice", "m1", 1, 2, "b".into(), MAX), Err(FragmentError::Inconsistent { .. })));
+        // 其他发送者不能往别人的消息里插入分片
+        assert!(matches!(reassembler.insert("mallory", "m1", 1, 3, "b".into(), MAX), Err(FragmentError::Inconsistent { .. })));
+    }
+
+    #[test]
+    fn rejects_a_total_above_the_maximum_without_storing_it() {
+        let mut reassembler = Reassembler::new();
+        let result = reassembler.insert("alice", "m1", 0, u16::MAX, "a".into(), MAX);
+        assert!(matches!(result, Err(FragmentError::TooLarge { .. })));
+        assert!(reassembler.partial.is_empty());
+    }
+
+    #[test]
+    fn drops_a_message_that_grows_past_the_maximum() {
+        let mut reassembler = Reassembler::new();
+        let max = FRAGMENT_CONTENT_SIZE + 1;
+        reassembler.insert("alice", "m1", 0, 2, "a".repeat(FRAGMENT_CONTENT_SIZE), max).unwrap();
+        let result = reassembler.insert("alice", "m1", 1, 2, "bc".into(), max);
+        assert!(matches!(result, Err(FragmentError::TooLarge { .. })));
+        assert!(reassembler.partial.is_empty());
+    }
+
+    #[test]
+    fn limits_open_messages_per_sender() {
+        let mut reassembler = Reassembler::new();
+        for n in 0..MAX_PARTIAL_PER_SENDER {
+            reassembler.insert("alice", &format!("m{}", n), 0, 2, "a".into(), MAX).unwrap();
+        }
+        let result = reassembler.insert("alice", "extra", 0, 2, "a".into(), MAX);
+        assert!(matches!(result, Err(FragmentError::TooManyOpen { .. })));
+        // 已经打开的消息和其他发送者不受影响
+        assert_eq!(reassembler.insert("alice", "m0", 1, 2, "b".into(), MAX).unwrap().as_deref(), Some("ab"));
+        assert!(reassembler.insert("bob", "other", 0, 2, "a".into(), MAX).unwrap().is_none());
+    }
+
+    #[test]
+    fn prune_reports_expired_sets() {
+        let mut reassembler = Reassembler::new();
+        reassembler.insert("alice", "m1", 0, 2, "a".into(), MAX).unwrap();
+        assert!(reassembler.prune(REASSEMBLY_TIMEOUT).is_empty());
+        std::thread::sleep(std::time::Duration::from_millis(5));
+        assert_eq!(reassembler.prune(Duration::from_millis(1)), vec![("m1".to_string(), "alice".to_string())]);
+        assert!(reassembler.partial.is_empty());
+    }
+}
@@ -0,0 +1,234 @@
+// history.rs
+use rusqlite::{params, Connection};
+use std::time::{Duration, Instant};
+use crate::profile::{ensure_private, Profile};
+use crate::protocol::unix_time;
+
+const HISTORY_FILE: &str = "history.db";
+// Bumped when the schema changes; 1 added the full-text index
+const SCHEMA_VERSION: i64 = 1;
+// 过期消息的清理间隔
+const PRUNE_INTERVAL: Duration = Duration::from_secs(3600);
+
+// Conversation key of outgoing broadcasts; rooms use "#<room>" and direct
+// messages the peer UUID, so the three can never collide
+pub const BROADCAST_CONVERSATION: &str = "*";
+
+pub fn room_conversation(room: &str) -> String {
+    format!("#{}", room)
+}
+
+// Filters of a full-text search; unset ones match everything
+#[derive(Debug, Clone, Default)]
+pub struct SearchQuery {
+    pub text: String,
+    pub sender: Option<String>, // UUID
+    pub room: Option<String>,
+    pub since: Option<u64>, // Unix seconds, inclusive
+    pub until: Option<u64>, // Unix seconds, exclusive
+    pub limit: usize,
+}
+
+#[derive(Debug, Clone)]
+pub struct SearchHit {
+    pub timestamp: u64,
+    pub conversation: String,
+    pub sender: String,
+    // Part of the body around the match, matched words in [brackets]
+    pub snippet: String,
+}
+
+// Every word of the input must appear; quoting each one keeps FTS5 operators
+// and punctuation typed by the user from being parsed as query syntax
+fn fts_query(text: &str) -> String {
+    text.split_whitespace()
+        .map(|word| format!("\"{}\"", word.replace('"', "\"\"")))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+#[derive(Debug, Clone)]
+pub struct StoredMessage {
+    pub timestamp: u64, // Unix seconds
+    pub sender: String, // UUID
+    pub kind: String,   // direct, broadcast or room
+    pub text: String,
+}
+
+// Opt-in store of sent and received messages in the profile directory.
+// Nothing is written unless history is enabled in the config.
+pub struct History {
+    conn: Connection,
+    // Messages older than this are deleted; None keeps everything
+    retention: Option<Duration>,
+    last_prune: Instant,
+}
+
+impl History {
+    pub fn open(profile: &Profile, retention: Option<Duration>) -> Result<Self, String> {
+        let path = profile.path(HISTORY_FILE);
+        // 先创建仅属主可读的空文件再交给 SQLite，日志文件也沿用这个权限
+        ensure_private(&path).map_err(|e| format!("Failed to protect {}: {}", path.display(), e))?;
+        let conn = Connection::open(&path).map_err(|e| format!("Failed to open {}: {}", path.display(), e))?;
+        conn.execute_batch(
+            "CREATE TABLE IF NOT EXISTS messages (
+                 id INTEGER PRIMARY KEY,
+                 message_id TEXT NOT NULL,
+                 timestamp INTEGER NOT NULL,
+                 conversation TEXT NOT NULL,
+                 sender TEXT NOT NULL,
+                 kind TEXT NOT NULL,
+                 room TEXT,
+                 body TEXT NOT NULL
+             );
+             CREATE INDEX IF NOT EXISTS messages_conversation ON messages (conversation, timestamp);
+             CREATE INDEX IF NOT EXISTS messages_timestamp ON messages (timestamp);
+             CREATE INDEX IF NOT EXISTS messages_sender ON messages (sender, timestamp);
+             -- 全文索引只保存 rowid，正文仍在 messages 表中
+             CREATE VIRTUAL TABLE IF NOT EXISTS messages_fts USING fts5 (body, content = 'messages', content_rowid = 'id');
+             CREATE TRIGGER IF NOT EXISTS messages_fts_insert AFTER INSERT ON messages BEGIN
+                 INSERT INTO messages_fts (rowid, body) VALUES (new.id, new.body);
+             END;
+             CREATE TRIGGER IF NOT EXISTS messages_fts_delete AFTER DELETE ON messages BEGIN
+                 INSERT INTO messages_fts (messages_fts, rowid, body) VALUES ('delete', old.id, old.body);
+             END;",
+        ).map_err(|e| format!("Failed to prepare history: {}", e))?;
+        // Stores written before the index existed get it filled in once
+        let version: i64 = conn.query_row("PRAGMA user_version", [], |row| row.get(0))
+            .map_err(|e| format!("Failed to prepare history: {}", e))?;
+        if version < SCHEMA_VERSION {
+            conn.execute_batch(&format!(
+                "INSERT INTO messages_fts (messages_fts) VALUES ('rebuild'); PRAGMA user_version = {};",
+                SCHEMA_VERSION
+            )).map_err(|e| format!("Failed to index history: {}", e))?;
+        }
+        let mut history = History { conn, retention, last_prune: Instant::now() };
+        history.prune()?;
+        Ok(history)
+    }
+
+    // Delete messages past the retention period
+    pub fn prune(&mut self) -> Result<usize, String> {
+        self.last_prune = Instant::now();
+        let Some(retention) = self.retention else {
+            return Ok(0);
+        };
+        let cutoff = unix_time().saturating_sub(retention.as_secs());
+        self.conn.execute("DELETE FROM messages WHERE timestamp < ?1", params![cutoff as i64])
+            .map_err(|e| format!("Failed to prune history: {}", e))
+    }
+
+    pub fn record(&mut self, message_id: &str, conversation: &str, sender: &str, kind: &str, room: Option<&str>, text: &str) -> Result<(), String> {
+        if self.last_prune.elapsed() > PRUNE_INTERVAL {
+            self.prune()?;
+        }
+        self.conn.execute(
+            "INSERT INTO messages (message_id, timestamp, conversation, sender, kind, room, body)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+            params![message_id, unix_time() as i64, conversation, sender, kind, room, text],
+        ).map(|_| ()).map_err(|e| format!("Failed to store message: {}", e))
+    }
+
+    // The last `count` messages of a conversation, oldest first
+    pub fn recent(&self, conversation: &str, count: usize) -> Result<Vec<StoredMessage>, String> {
+        let mut statement = self.conn.prepare(
+            "SELECT timestamp, sender, kind, body FROM (
+                 SELECT id, timestamp, sender, kind, body FROM messages
+                 WHERE conversation = ?1 ORDER BY timestamp DESC, id DESC LIMIT ?2
+             ) ORDER BY timestamp, id",
+        ).map_err(|e| e.to_string())?;
+        let rows = statement.query_map(params![conversation, count as i64], |row| {
+            Ok(StoredMessage {
+                timestamp: row.get::<_, i64>(0)? as u64,
+                sender: row.get(1)?,
+                kind: row.get(2)?,
+                text: row.get(3)?,
+            })
+        }).map_err(|e| e.to_string())?;
+        rows.collect::<Result<_, _>>().map_err(|e| format!("Failed to read history: {}", e))
+    }
+
+    // Newest matches first
+    pub fn search(&self, query: &SearchQuery) -> Result<Vec<SearchHit>, String> {
+        let text = fts_query(&query.text);
+        if text.is_empty() {
+            return Err("Nothing to search for".to_string());
+        }
+        let mut statement = self.conn.prepare(
+            "SELECT m.timestamp, m.conversation, m.sender, snippet(messages_fts, 0, '[', ']', '...', 16)
+             FROM messages_fts JOIN messages m ON m.id = messages_fts.rowid
+             WHERE messages_fts MATCH ?1
+               AND (?2 IS NULL OR m.sender = ?2)
+               AND (?3 IS NULL OR m.room = ?3)
+               AND (?4 IS NULL OR m.timestamp >= ?4)
+               AND (?5 IS NULL OR m.timestamp < ?5)
+             ORDER BY m.timestamp DESC, m.id DESC
+             LIMIT ?6",
+        ).map_err(|e| e.to_string())?;
+        let rows = statement.query_map(
+            params![
+                text,
+                query.sender,
+                query.room,
+                query.since.map(|t| t as i64),
+                query.until.map(|t| t as i64),
+                query.limit as i64,
+            ],
+            |row| Ok(SearchHit {
+                timestamp: row.get::<_, i64>(0)? as u64,
+                conversation: row.get(1)?,
+                sender: row.get(2)?,
+                snippet: row.get(3)?,
+            }),
+        ).map_err(|e| e.to_string())?;
+        rows.collect::<Result<_, _>>().map_err(|e| format!("Failed to search history: {}", e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::profile::TestDir;
+
+    fn query(text: &str) -> SearchQuery {
+        SearchQuery { text: text.to_string(), sender: None, room: None, since: None, until: None, limit: 20 }
+    }
+
+    #[test]
+    fn fts_query_quotes_every_word() {
+        assert_eq!(fts_query("hello  world"), r#""hello" "world""#);
+        assert_eq!(fts_query(r#"say "hi" OR NOT x*"#), r#""say" """hi""" "OR" "NOT" "x*""#);
+        assert_eq!(fts_query("   "), "");
+    }
+
+    #[test]
+    fn search_treats_operators_as_words() {
+        let dir = TestDir::new();
+        let mut history = History::open(&dir.profile(), None).unwrap();
+        history.record("m1", "bob", "bob", "direct", None, "lunch AND dinner? \"maybe\"").unwrap();
+        history.record("m2", "lobby", "alice", "room", Some("lobby"), "lunch at noon").unwrap();
+
+        assert_eq!(history.search(&query("lunch")).unwrap().len(), 2);
+        let hits = history.search(&query("AND \"maybe")).unwrap();
+        assert_eq!(hits.len(), 1);
+        assert_eq!(hits[0].sender, "bob");
+        assert!(history.search(&query("dinner? (")).unwrap().len() == 1);
+        let in_room = SearchQuery { room: Some("lobby".to_string()), ..query("lunch") };
+        assert_eq!(history.search(&in_room).unwrap()[0].conversation, "lobby");
+        assert!(history.search(&query(" ")).is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn the_database_is_private_from_the_start() {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = TestDir::new();
+        let mut history = History::open(&dir.profile(), None).unwrap();
+        history.record("m1", "bob", "bob", "direct", None, "hello").unwrap();
+        let mode = std::fs::metadata(dir.join(HISTORY_FILE)).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        drop(history);
+        // 重新打开已有的数据库
+        History::open(&dir.profile(), None).unwrap();
+    }
+}
@@ -0,0 +1,88 @@
+// identity.rs
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey, PUBLIC_KEY_LENGTH, SECRET_KEY_LENGTH};
+use rand_core::OsRng;
+use sha2::{Digest, Sha256};
+use std::fs;
+use std::io::{self, Write};
+use uuid::Uuid;
+use crate::profile::{create_private, Profile};
+
+const IDENTITY_FILE: &str = "identity.key";
+
+// Long-term Ed25519 keypair; the node ID is derived from the public key
+pub struct Identity {
+    signing_key: SigningKey,
+}
+
+impl Identity {
+    // 从 profile 目录读取密钥，不存在时生成新的密钥并保存
+    pub fn load_or_create(profile: &Profile) -> io::Result<Self> {
+        let path = profile.path(IDENTITY_FILE);
+        match fs::read_to_string(&path) {
+            Ok(text) => {
+                let bytes = hex::decode(text.trim())
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+                let secret: [u8; SECRET_KEY_LENGTH] = bytes.try_into().map_err(|_| {
+                    io::Error::new(io::ErrorKind::InvalidData, "identity key has the wrong length")
+                })?;
+                Ok(Identity { signing_key: SigningKey::from_bytes(&secret) })
+            },
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                let identity = Identity { signing_key: SigningKey::generate(&mut OsRng) };
+                let mut file = create_private(&path)?;
+                file.write_all(hex::encode(identity.signing_key.to_bytes()).as_bytes())?;
+                file.sync_all()?;
+                Ok(identity)
+            },
+            Err(e) => Err(e),
+        }
+    }
+
+    pub fn node_id(&self) -> String {
+        node_id_for(&self.signing_key.verifying_key())
+    }
+
+    pub fn public_key(&self) -> VerifyingKey {
+        self.signing_key.verifying_key()
+    }
+
+    pub fn public_key_hex(&self) -> String {
+        hex::encode(self.signing_key.verifying_key().as_bytes())
+    }
+
+    pub fn sign_hex(&self, data: &[u8]) -> String {
+        hex::encode(self.signing_key.sign(data).to_bytes())
+    }
+}
+
+// The first 16 bytes of SHA-256(public key), formatted as a UUID
+pub fn node_id_for(public_key: &VerifyingKey) -> String {
+    let digest = Sha256::digest(public_key.as_bytes());
+    let mut bytes = [0u8; 16];
+    bytes.copy_from_slice(&digest[..16]);
+    Uuid::from_bytes(bytes).to_string()
+}
+
+// Human-comparable SHA-256 of a public key, in groups of four hex digits
+pub fn fingerprint(public_key: &VerifyingKey) -> String {
+    let digest = hex::encode(Sha256::digest(public_key.as_bytes()));
+    digest.as_bytes()
+        .chunks(4)
+        .map(|group| std::str::from_utf8(group).expect("hex is ASCII"))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+pub fn parse_public_key(public_key: &str) -> Result<VerifyingKey, String> {
+    let bytes: [u8; PUBLIC_KEY_LENGTH] = hex::decode(public_key)
+        .map_err(|e| format!("invalid public key: {}", e))?
+        .try_into()
+        .map_err(|_| "public key has the wrong length".to_string())?;
+    VerifyingKey::from_bytes(&bytes).map_err(|e| format!("invalid public key: {}", e))
+}
+
+pub fn verify(public_key: &VerifyingKey, data: &[u8], signature: &str) -> Result<(), String> {
+    let bytes = hex::decode(signature).map_err(|e| format!("invalid signature: {}", e))?;
+    let signature = Signature::from_slice(&bytes).map_err(|e| format!("invalid signature: {}", e))?;
+    public_key.verify(data, &signature).map_err(|_| "signature does not match".to_string())
+}
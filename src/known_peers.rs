@@ -0,0 +1,130 @@
+// known_peers.rs
+use ed25519_dalek::VerifyingKey;
+use serde::{Serialize, Deserialize};
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use crate::identity;
+use crate::profile::Profile;
+use crate::protocol::unix_time;
+
+const KNOWN_PEERS_FILE: &str = "known_peers.json";
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct KnownPeer {
+    pub public_key: String,
+    pub fingerprint: String,
+    pub verified: bool,
+    pub first_seen: u64, // Unix seconds
+}
+
+pub enum TrustCheck {
+    // First time we see this peer; its key has just been recorded
+    New,
+    Known,
+    // The peer ID was recorded with a different key
+    Mismatch { expected: String, got: String },
+}
+
+// Trust-on-first-use store of peer keys, persisted in the profile directory
+pub struct KnownPeers {
+    path: PathBuf,
+    peers: HashMap<String, KnownPeer>,
+}
+
+impl KnownPeers {
+    pub fn load(profile: &Profile) -> io::Result<Self> {
+        let path = profile.path(KNOWN_PEERS_FILE);
+        let peers = match fs::read(&path) {
+            Ok(data) => serde_json::from_slice(&data).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => HashMap::new(),
+            Err(e) => return Err(e),
+        };
+        Ok(KnownPeers { path, peers })
+    }
+
+    fn save(&self) -> io::Result<()> {
+        fs::write(&self.path, serde_json::to_vec_pretty(&self.peers)?)
+    }
+
+    pub fn get(&self, uuid: &str) -> Option<&KnownPeer> {
+        self.peers.get(uuid)
+    }
+
+    // UUIDs of every peer whose key has been recorded
+    pub fn uuids(&self) -> impl Iterator<Item = &String> {
+        self.peers.keys()
+    }
+
+    // Compare `key` against the recorded one without recording anything
+    pub fn lookup(&self, uuid: &str, key: &VerifyingKey) -> TrustCheck {
+        match self.peers.get(uuid) {
+            None => TrustCheck::New,
+            Some(known) if known.public_key == hex::encode(key.as_bytes()) => TrustCheck::Known,
+            Some(known) => TrustCheck::Mismatch {
+                expected: known.fingerprint.clone(),
+                got: identity::fingerprint(key),
+            },
+        }
+    }
+
+    // Compare `key` against the recorded one, recording it if the peer is new
+    pub fn check(&mut self, uuid: &str, key: &VerifyingKey) -> io::Result<TrustCheck> {
+        let check = self.lookup(uuid, key);
+        if !matches!(check, TrustCheck::New) {
+            return Ok(check);
+        }
+
+        let public_key = hex::encode(key.as_bytes());
+        let first_seen = unix_time();
+        self.peers.insert(uuid.to_string(), KnownPeer {
+            public_key,
+            fingerprint: identity::fingerprint(key),
+            verified: false,
+            first_seen,
+        });
+        self.save()?;
+        Ok(TrustCheck::New)
+    }
+
+    pub fn mark_verified(&mut self, uuid: &str) -> Result<(), String> {
+        let peer = self.peers.get_mut(uuid).ok_or_else(|| format!("No recorded key for {}", uuid))?;
+        peer.verified = true;
+        self.save().map_err(|e| format!("Failed to save known peers: {}", e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::profile::TestDir;
+    use ed25519_dalek::SigningKey;
+
+    fn key(seed: u8) -> VerifyingKey {
+        SigningKey::from_bytes(&[seed; 32]).verifying_key()
+    }
+
+    #[test]
+    fn records_the_first_key_and_rejects_a_different_one() {
+        let dir = TestDir::new();
+        let mut peers = KnownPeers::load(&dir.profile()).unwrap();
+        assert!(matches!(peers.lookup("bob", &key(1)), TrustCheck::New));
+        assert!(peers.get("bob").is_none());
+        assert!(matches!(peers.check("bob", &key(1)).unwrap(), TrustCheck::New));
+        assert!(matches!(peers.check("bob", &key(1)).unwrap(), TrustCheck::Known));
+        match peers.check("bob", &key(2)).unwrap() {
+            TrustCheck::Mismatch { expected, got } => {
+                assert_eq!(expected, identity::fingerprint(&key(1)));
+                assert_eq!(got, identity::fingerprint(&key(2)));
+            },
+            _ => panic!("a changed key must not be accepted"),
+        }
+
+        // 记录的密钥在重启后仍然有效，新的密钥不会覆盖它
+        peers.mark_verified("bob").unwrap();
+        let peers = KnownPeers::load(&dir.profile()).unwrap();
+        assert!(peers.get("bob").unwrap().verified);
+        assert!(matches!(peers.lookup("bob", &key(2)), TrustCheck::Mismatch { .. }));
+    }
+}
@@ -0,0 +1,35 @@
+// lib.rs
+// A LAN chat node that can be embedded in other tools: build a ChatNode, send
+// messages and follow its events. The terminal and full-screen frontends the
+// P2PChatBot binary uses are built on the same API.
+mod chat_node;
+mod command_registry;
+mod commands;
+mod config;
+mod console;
+mod delivery;
+mod events;
+mod fragment;
+mod history;
+mod identity;
+mod known_peers;
+mod multicast_discovery;
+mod network;
+mod node_manager;
+mod pending;
+mod profile;
+mod protocol;
+mod rooms;
+mod session;
+pub mod terminal;
+pub mod tui;
+mod udp_connection;
+
+pub use chat_node::{ChatNode, ChatNodeBuilder, EventStream, Transport};
+pub use config::Config;
+pub use delivery::DeliveryStatus;
+pub use events::{Conversation, Event};
+pub use identity::Identity;
+pub use network::Interface;
+pub use node_manager::{Peer, SendOutcome};
+pub use profile::Profile;
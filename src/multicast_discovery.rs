@@ -1,74 +1,243 @@
 use tokio::net::UdpSocket;
-use tokio::time::{self, Duration, Instant};
-use std::collections::HashMap;
-use serde_json::{from_str, to_string};
-use tokio::sync::mpsc;
+use tokio::time;
 use tokio::sync::Mutex;
 use std::sync::Arc;
-use std::net::SocketAddr;
-use std::net::Ipv4Addr;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
 use tokio::io::{self, ErrorKind};
-use crate::node_manager::{Message, NodeInfo,NodeManager};
+use ed25519_dalek::VerifyingKey;
+use log::{debug, warn};
+use crate::config::Config;
+use crate::events::Event;
+use crate::identity::{self, Identity};
+use crate::network::{self, Interface};
+use crate::node_manager::NodeManager;
+use crate::protocol::{check_timestamp, presence_signing_bytes, unix_time, DecodeError, Envelope, Payload, PresenceInfo};
+use crate::udp_connection::RECV_BUFFER_SIZE;
+
+// The signed address must be the one the datagram came from; anything else is
+// a captured announcement re-sent by another host
+fn check_source(envelope: &Envelope, src: SocketAddr) -> Result<(), String> {
+    match src.ip() {
+        IpAddr::V4(ip) if ip == envelope.ip => Ok(()),
+        ip => Err(format!("signed for {} but sent from {}", envelope.ip, ip)),
+    }
+}
+
+// Check that an announcement was signed by the key its sender ID is derived from.
+// Freshness is checked by the caller, which reports a peer whose clock is off.
+fn verify_announce(envelope: &Envelope, src: SocketAddr, public_key: &str, timestamp: u64, info: &PresenceInfo, signature: &str) -> Result<VerifyingKey, String> {
+    let key = identity::parse_public_key(public_key)?;
+    if identity::node_id_for(&key) != envelope.sender {
+        return Err("node ID is not derived from the announced key".to_string());
+    }
+    let data = presence_signing_bytes("announce", &envelope.sender, envelope.ip, envelope.port, public_key, timestamp, info);
+    identity::verify(&key, &data, signature)?;
+    check_source(envelope, src)?;
+    Ok(key)
+}
+
+fn verify_goodbye(envelope: &Envelope, src: SocketAddr, key: &VerifyingKey, timestamp: u64, signature: &str) -> Result<(), String> {
+    check_timestamp(timestamp)?;
+    let public_key = hex::encode(key.as_bytes());
+    let data = presence_signing_bytes("goodbye", &envelope.sender, envelope.ip, envelope.port, &public_key, timestamp, &PresenceInfo::default());
+    identity::verify(key, &data, signature)?;
+    check_source(envelope, src)
+}
 
 pub async fn network_monitor(
-    notify_tx: mpsc::Sender<String>,
     node_manager: Arc<Mutex<NodeManager>>,
-    name:String
+    name:String,
+    config: Arc<Config>,
+    interfaces: Vec<Interface>,
 ) -> tokio::io::Result<()> {
-    let socket = UdpSocket::bind("0.0.0.0:3000").await?;
-    socket.join_multicast_v4("239.255.255.250".parse().unwrap(), "0.0.0.0".parse().unwrap())?;
+    let group = match parse_multicast_addr(&config.multicast_addr)? {
+        SocketAddr::V4(group) => group,
+        SocketAddr::V6(_) => return Err(io::Error::new(ErrorKind::InvalidInput, "multicast group must be IPv4")),
+    };
+    // 在每个选定的网卡上加入组播组
+    let socket = network::multicast_listener(group, &interfaces)?;
 
-    let mut buf = [0u8; 1024];
-    let mut interval = time::interval(Duration::from_secs(10));
+    let mut buf = vec![0u8; RECV_BUFFER_SIZE];
+    let mut interval = time::interval(config.check_interval());
 
     loop {
         tokio::select! {
-            Ok((size, _)) = socket.recv_from(&mut buf) => {
-                if let Ok(msg_str) = String::from_utf8(buf[..size].to_vec()) {
-                    if let Ok(message) = from_str::<Message>(&msg_str) {
-                        let node_name = &message.name;
-                        if name == *node_name{
+            Ok((size, src)) = socket.recv_from(&mut buf) => {
+                let envelope = match Envelope::decode(&buf[..size]) {
+                    Ok(envelope) => envelope,
+                    // 未签名的内容不能用来更新节点表，只记录日志
+                    Err(DecodeError::Malformed(_)) => continue,
+                    Err(e) => {
+                        warn!("Ignoring presence from {}: {}", src, e);
+                        continue;
+                    },
+                };
+                if name == envelope.sender {
+                    continue;
+                }
+                let node_manager = node_manager.lock().await; // 先获取锁
+                match &envelope.payload {
+                    Payload::Announce { public_key, timestamp, info, signature } => {
+                        let key = match verify_announce(&envelope, src, public_key, *timestamp, info, signature) {
+                            Ok(key) => key,
+                            Err(e) => {
+                                warn!("Rejected announcement for {} from {}: {}", envelope.sender, src, e);
+                                continue;
+                            }
+                        };
+                        if let Err(e) = check_timestamp(*timestamp) {
+                            warn!("Rejected announcement for {} from {}: {}", envelope.sender, src, e);
+                            node_manager.report_clock_skew(&envelope.sender, *timestamp).await;
                             continue;
                         }
-                        let node_manager = node_manager.lock().await; // 先获取锁
-                        if let Ok(true) = node_manager.add_or_update_node(message.name.clone(), message.ip, message.port).await {
-                            notify_tx.send(format!("Node {} came online!", message.name)).await.unwrap();
+                        // 重放的旧公告不能把节点指向别处；同一数据报从多个网卡收到时也会走到这里
+                        if !node_manager.accept_presence(&envelope.sender, envelope.ip, *timestamp, false).await {
+                            debug!("Ignoring announcement for {} from {} that is not newer than the last one", envelope.sender, src);
+                            continue;
                         }
-                    }
+                        let ip = envelope.ip;
+                        let info = info.clone().sanitized();
+                        let display_name = info.display_name.clone();
+                        match node_manager.add_or_update_node(envelope.sender.clone(), ip, envelope.port, key, info).await {
+                            Ok(new) => {
+                                if new {
+                                    node_manager.publish(Event::PeerOnline { uuid: envelope.sender.clone(), display_name });
+                                }
+                                // 节点上线时投递离线期间排队的消息，之后每次公告时重试投递失败后放回队列的消息
+                                node_manager.flush_pending(&envelope.sender).await;
+                            },
+                            Err(e) => {
+                                warn!("Announcement from {} rejected: {}", src, e);
+                                node_manager.publish(Event::PeerRejected { reason: e });
+                            },
+                        }
+                    },
+                    Payload::Goodbye { timestamp, signature } => {
+                        let Some(node) = node_manager.nodes.lock().await.get(&envelope.sender).cloned() else {
+                            continue;
+                        };
+                        if let Err(e) = verify_goodbye(&envelope, src, &node.public_key, *timestamp, signature) {
+                            warn!("Rejected goodbye for {} from {}: {}", envelope.sender, src, e);
+                            continue;
+                        }
+                        if !node_manager.accept_presence(&envelope.sender, envelope.ip, *timestamp, true).await {
+                            debug!("Ignoring goodbye for {} from {} older than its last announcement", envelope.sender, src);
+                            continue;
+                        }
+                        if let Some(node) = node_manager.remove_node(&envelope.sender).await {
+                            node_manager.publish(Event::PeerOffline { uuid: envelope.sender.clone(), display_name: node.info.display_name });
+                        }
+                    },
+                    Payload::RoomChat { .. } => node_manager.receive_room_chat(&envelope).await,
+                    other => warn!("Unexpected {:?} from {} on multicast group", other, envelope.sender),
                 }
             },
             _ = interval.tick() => {
-                let node_manager = node_manager.lock().await; // 先获取锁
-                node_manager.check_and_notify_offline_nodes(&notify_tx).await;
+                node_manager.lock().await.check_offline_nodes().await;
             }
         }
     }
 }
 
+fn parse_multicast_addr(multicast_addr: &str) -> io::Result<SocketAddr> {
+    multicast_addr.parse().map_err(|e| {
+        io::Error::new(ErrorKind::InvalidInput, e)
+    })
+}
 
-pub async fn multicast_sender(multicast_addr: &str, communication_ip:  String, communication_port: u16,node_name:String,) -> tokio::io::Result<()> {
-    let multicast_socket = UdpSocket::bind("0.0.0.0:0").await?;
-    // multicast_socket.set_multicast_loop_v4(false)?;
+fn signed_presence(identity: &Identity, kind: &str, ip: Ipv4Addr, port: u16, info: &PresenceInfo) -> Envelope {
+    let node_name = identity.node_id();
+    let public_key = identity.public_key_hex();
+    let timestamp = unix_time();
+    let signature = identity.sign_hex(&presence_signing_bytes(kind, &node_name, ip, port, &public_key, timestamp, info));
+    let payload = if kind == "goodbye" {
+        Payload::Goodbye { timestamp, signature }
+    } else {
+        Payload::Announce { public_key, timestamp, info: info.clone(), signature }
+    };
+    Envelope::new(node_name, ip, port, payload)
+}
 
-    let multicast_addr: SocketAddr = multicast_addr.parse().map_err(|e| {
-        io::Error::new(ErrorKind::InvalidInput, e)
-    })?;
+// A bound chat socket is the only place peers can reach us, so with a bind
+// address a single socket sends from and announces it. Otherwise there is one
+// socket per chosen interface, each announcing that interface's address, or
+// without a selection a single default socket announcing `communication_ip`.
+fn sender_sockets(communication_ip: Ipv4Addr, bind_addr: Option<Ipv4Addr>, interfaces: &[Interface]) -> io::Result<Vec<(UdpSocket, Ipv4Addr)>> {
+    if let Some(ip) = bind_addr {
+        return Ok(vec![(network::multicast_sender_socket(ip)?, ip)]);
+    }
+    if interfaces.is_empty() {
+        let socket = std::net::UdpSocket::bind("0.0.0.0:0")?;
+        socket.set_nonblocking(true)?;
+        return Ok(vec![(UdpSocket::from_std(socket)?, communication_ip)]);
+    }
+    interfaces.iter()
+        .map(|iface| Ok((network::multicast_sender_socket(iface.ip)?, iface.ip)))
+        .collect()
+}
+
+pub async fn multicast_sender(
+    config: Arc<Config>,
+    communication_ip: String,
+    communication_port: u16,
+    identity: Arc<Identity>,
+    interfaces: Vec<Interface>,
+    presence: Arc<Mutex<PresenceInfo>>,
+) -> tokio::io::Result<()> {
+    let multicast_addr = parse_multicast_addr(&config.multicast_addr)?;
 
-    let mut interval = time::interval(Duration::from_secs(5));
+    let mut interval = time::interval(config.announce_interval());
     let communication_ip: Ipv4Addr = communication_ip.parse().map_err(|_e| {
         tokio::io::Error::new(tokio::io::ErrorKind::InvalidInput, "Invalid IP address")
     })?;
-    
+    let sockets = sender_sockets(communication_ip, config.bind_addr, &interfaces)?;
+
     loop {
         interval.tick().await;
-        let message = Message {
-            ip: communication_ip,
-            port: communication_port,
-            name: node_name.clone(),
-            content: format!("Node is online at {}:{}", communication_ip, communication_port),
-        };
-        let message_json = to_string(&message)?;
-        multicast_socket.send_to(&message_json.as_bytes(), &multicast_addr).await?;
-        // println!("Multicast message sent: {:?}", message);
+        // 每次发送前读取最新状态，加入或离开房间后自动生效
+        let info = presence.lock().await.clone();
+        for (multicast_socket, ip) in &sockets {
+            let message = signed_presence(&identity, "announce", *ip, communication_port, &info);
+            let message_json = message.encode()?;
+            if let Err(e) = multicast_socket.send_to(&message_json, &multicast_addr).await {
+                warn!("Failed to announce on {}: {}", ip, e);
+            }
+        }
+    }
+}
+
+// Tell the group we are leaving so peers drop us without waiting for the timeout
+pub async fn send_goodbye(config: &Config, communication_ip: Ipv4Addr, communication_port: u16, identity: &Identity, interfaces: &[Interface]) -> tokio::io::Result<()> {
+    let multicast_addr = parse_multicast_addr(&config.multicast_addr)?;
+    for (multicast_socket, ip) in sender_sockets(communication_ip, config.bind_addr, interfaces)? {
+        let message = signed_presence(identity, "goodbye", ip, communication_port, &PresenceInfo::default());
+        multicast_socket.send_to(&message.encode()?, &multicast_addr).await?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::profile::TestDir;
+
+    #[tokio::test]
+    async fn announcements_leave_from_the_bound_address() {
+        let identity = Identity::load_or_create(&TestDir::new().profile()).unwrap();
+        let sockets = sender_sockets(Ipv4Addr::LOCALHOST, Some(Ipv4Addr::LOCALHOST), &[]).unwrap();
+        assert_eq!(sockets.len(), 1);
+        let (socket, ip) = &sockets[0];
+        let envelope = signed_presence(&identity, "announce", *ip, 9000, &PresenceInfo::default());
+        assert_eq!(check_source(&envelope, socket.local_addr().unwrap()), Ok(()));
+        assert!(check_source(&envelope, "192.0.2.2:9000".parse().unwrap()).is_err());
+    }
+
+    #[tokio::test]
+    async fn a_bind_address_is_announced_instead_of_the_interfaces() {
+        let other = Interface { name: "eth9".to_string(), ip: Ipv4Addr::new(192, 0, 2, 1), netmask: Ipv4Addr::new(255, 255, 255, 0), is_loopback: false };
+        let sockets = sender_sockets(Ipv4Addr::LOCALHOST, Some(Ipv4Addr::LOCALHOST), &[other]).unwrap();
+        assert_eq!(sockets.len(), 1);
+        assert_eq!(sockets[0].1, Ipv4Addr::LOCALHOST);
+        assert_eq!(sockets[0].0.local_addr().unwrap().ip(), IpAddr::V4(Ipv4Addr::LOCALHOST));
     }
 }
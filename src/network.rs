@@ -0,0 +1,115 @@
+// network.rs
+use if_addrs::IfAddr;
+use socket2::{Domain, Protocol, Socket, Type};
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4};
+use tokio::net::UdpSocket;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Interface {
+    pub name: String,
+    pub ip: Ipv4Addr,
+    pub netmask: Ipv4Addr,
+    pub is_loopback: bool,
+}
+
+impl Interface {
+    // Whether `ip` is on this interface's subnet
+    pub fn contains(&self, ip: Ipv4Addr) -> bool {
+        let mask = u32::from(self.netmask);
+        u32::from(self.ip) & mask == u32::from(ip) & mask
+    }
+}
+
+// All local IPv4 interfaces
+pub fn list_interfaces() -> io::Result<Vec<Interface>> {
+    let mut interfaces: Vec<Interface> = if_addrs::get_if_addrs()?
+        .into_iter()
+        .filter_map(|iface| match &iface.addr {
+            IfAddr::V4(addr) => Some(Interface {
+                is_loopback: iface.is_loopback(),
+                ip: addr.ip,
+                netmask: addr.netmask,
+                name: iface.name,
+            }),
+            IfAddr::V6(_) => None,
+        })
+        .collect();
+    interfaces.sort_by(|a, b| a.name.cmp(&b.name).then(a.ip.cmp(&b.ip)));
+    Ok(interfaces)
+}
+
+// Resolve interface names or addresses to local interfaces; every entry must match
+pub fn select_interfaces(wanted: &[String]) -> io::Result<Vec<Interface>> {
+    let available = list_interfaces()?;
+    let mut selected: Vec<Interface> = Vec::new();
+    for want in wanted {
+        let matches: Vec<&Interface> = available.iter()
+            .filter(|iface| iface.name == *want || iface.ip.to_string() == *want)
+            .collect();
+        if matches.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::NotFound, format!("no IPv4 interface named {}", want)));
+        }
+        for iface in matches {
+            if !selected.contains(iface) {
+                selected.push(iface.clone());
+            }
+        }
+    }
+    Ok(selected)
+}
+
+// Bind the multicast port with address reuse so several nodes can share one host,
+// then join the group on each chosen interface (or the default one)
+pub fn multicast_listener(group: SocketAddrV4, interfaces: &[Interface]) -> io::Result<UdpSocket> {
+    let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+    socket.set_reuse_address(true)?;
+    #[cfg(unix)]
+    socket.set_reuse_port(true)?;
+    socket.bind(&SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, group.port()).into())?;
+    if interfaces.is_empty() {
+        socket.join_multicast_v4(group.ip(), &Ipv4Addr::UNSPECIFIED)?;
+    }
+    for iface in interfaces {
+        socket.join_multicast_v4(group.ip(), &iface.ip)?;
+    }
+    socket.set_nonblocking(true)?;
+    UdpSocket::from_std(socket.into())
+}
+
+// A socket bound to `ip` whose multicast traffic leaves through the interface with that address
+pub fn multicast_sender_socket(ip: Ipv4Addr) -> io::Result<UdpSocket> {
+    let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+    socket.set_multicast_if_v4(&ip)?;
+    socket.bind(&SocketAddrV4::new(ip, 0).into())?;
+    socket.set_nonblocking(true)?;
+    UdpSocket::from_std(socket.into())
+}
+
+// A chat socket bound to `iface`, whose multicast traffic also leaves through it
+pub fn chat_socket(iface: &Interface, port: u16) -> io::Result<UdpSocket> {
+    let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+    socket.set_multicast_if_v4(&iface.ip)?;
+    socket.bind(&SocketAddrV4::new(iface.ip, port).into())?;
+    socket.set_nonblocking(true)?;
+    UdpSocket::from_std(socket.into())
+}
+
+// Find the local address the OS would use to reach `target`. Connecting a UDP
+// socket only selects a route; nothing is sent.
+pub async fn detect_local_ip(target: SocketAddr) -> io::Result<Ipv4Addr> {
+    let socket = UdpSocket::bind("0.0.0.0:0").await?;
+    socket.connect(target).await?;
+    match socket.local_addr()?.ip() {
+        IpAddr::V4(ip) if !ip.is_unspecified() => Ok(ip),
+        ip => Err(io::Error::new(io::ErrorKind::AddrNotAvailable, format!("no usable route, got {}", ip))),
+    }
+}
+
+// The address a datagram really came from, falling back to what the sender claimed
+pub fn observed_ip(src: SocketAddr, claimed: Ipv4Addr) -> Ipv4Addr {
+    match src.ip() {
+        IpAddr::V4(ip) if !ip.is_unspecified() => ip,
+        _ => claimed,
+    }
+}
@@ -1,23 +1,30 @@
 // node_manager.rs
-use std::net::Ipv4Addr;
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
 use tokio::time::Instant;
-use tokio::sync::mpsc;
+use tokio::sync::broadcast;
 use std::sync::Arc;
 use tokio::sync::Mutex;
-use serde::{Serialize, Deserialize};
-use std::collections::HashMap;
-use crate::udp_connection;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use crate::events::{self, Conversation, Event};
+use crate::network;
+use crate::udp_connection::ChatSockets;
+use crate::delivery::{AckTracker, DeliveryStatus};
+use crate::identity::{self, Identity};
+use crate::history::{self, History, SearchQuery};
+use crate::pending::PendingQueue;
+use crate::profile::Profile;
+use crate::rooms::{self, RoomKey, Rooms};
+use crate::known_peers::{KnownPeers, TrustCheck};
+use crate::config::Config;
+use crate::fragment::{self, Reassembler, FRAGMENT_CONTENT_SIZE, REASSEMBLY_TIMEOUT};
+use crate::protocol::{check_timestamp, clean_message, room_signing_bytes, unix_time, ChatBody, MAX_CLOCK_SKEW, DecodeError, Envelope, Payload, PresenceInfo, Scope};
+use crate::session::{self, Session, Sessions};
 use tokio::time::{self, Duration};
-use tokio::net::UdpSocket;
-use std::io;
-
-#[derive(Serialize, Deserialize, Debug, Clone)]
-pub struct Message {
-    pub ip: Ipv4Addr,
-    pub port: u16,
-    pub name: String, // UUID
-    pub content: String,
-}
+use tokio::task::JoinHandle;
+use log::{info, warn};
+use ed25519_dalek::VerifyingKey;
+use uuid::Uuid;
 
 #[derive(Debug, Clone)]
 pub struct NodeInfo {
@@ -25,15 +32,265 @@ pub struct NodeInfo {
     pub port: u16,
     pub last_active: Instant,
     pub alias: Option<String>, // Alias is optional and not set by default
+    pub public_key: VerifyingKey, // Key that signed the node's announcements
+    pub info: PresenceInfo, // Display name and status the node advertises
 }
 
 impl NodeInfo {
-    pub fn new(ip: Ipv4Addr, port: u16) -> Self {
+    pub fn new(ip: Ipv4Addr, port: u16, public_key: VerifyingKey, info: PresenceInfo) -> Self {
         NodeInfo {
             ip,
             port,
             last_active: Instant::now(),
             alias: None, // Default to None
+            public_key,
+            info,
+        }
+    }
+
+    // 本地别名优先于对方自己声明的名称
+    pub fn name(&self) -> Option<&str> {
+        self.alias.as_deref().or(self.info.display_name.as_deref())
+    }
+}
+
+// An online peer, as handed out by the library API
+#[derive(Debug, Clone)]
+pub struct Peer {
+    pub uuid: String,
+    pub alias: Option<String>,
+    pub display_name: Option<String>,
+    pub status: Option<String>,
+    pub ip: Ipv4Addr,
+    pub port: u16,
+    // The key fingerprint has been checked with `verify`
+    pub verified: bool,
+}
+
+impl Peer {
+    pub fn name(&self) -> Option<&str> {
+        self.alias.as_deref().or(self.display_name.as_deref())
+    }
+}
+
+// 握手重试次数，首次等待 1 秒，之后每次翻倍
+const HANDSHAKE_ATTEMPTS: u32 = 3;
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(1);
+
+// Additional data bound into every sealed chat message
+fn chat_aad(sender: &str, recipient: &str, id: &str) -> String {
+    format!("{}|{}|{}", sender, recipient, id)
+}
+
+fn room_aad(room: &str, key_id: &str, sender: &str, id: &str) -> String {
+    format!("room|{}|{}|{}|{}", room, key_id, sender, id)
+}
+
+// 组播上的房间消息不分片也没有确认，限制在一个数据报以内
+const MAX_ROOM_CIPHERTEXT: usize = 8192;
+
+// What became of a direct message
+pub enum SendOutcome {
+    // Being delivered; the handle resolves once the peer acknowledges it or
+    // the retransmissions give up
+    Sending(JoinHandle<DeliveryStatus>),
+    // The peer is offline; the message waits in the pending queue under this number
+    Queued(u64),
+    // The peer is online but older messages to it are still queued; this one
+    // waits behind them under this number
+    QueuedBehind(u64),
+}
+
+// How a room message went out
+pub enum RoomDelivery {
+    // One reliable delivery per online member of an open room
+    Peers(Vec<(String, JoinHandle<DeliveryStatus>)>),
+    // A single unacknowledged datagram to the multicast group
+    Multicast,
+}
+
+fn chat_plaintext(content: &str, scope: Scope, max_message_size: usize) -> Result<Vec<u8>, String> {
+    let plaintext = serde_json::to_vec(&ChatBody { text: content.to_string(), scope })
+        .map_err(|e| format!("Failed to serialize message: {}", e))?;
+    if plaintext.len() > max_message_size {
+        return Err(format!(
+            "Message is {} bytes, larger than the maximum of {} bytes",
+            plaintext.len(), max_message_size
+        ));
+    }
+    Ok(plaintext)
+}
+
+// Everything a background delivery task needs, cloned out of NodeManager
+#[derive(Clone)]
+struct Outbound {
+    uuid: String,
+    ip: Ipv4Addr,
+    port: u16,
+    acks: AckTracker,
+    sessions: Sessions,
+    sockets: ChatSockets,
+}
+
+impl Outbound {
+    fn envelope(&self, payload: Payload) -> Envelope {
+        Envelope::new(self.uuid.clone(), self.ip, self.port, payload)
+    }
+
+    // Reuse the session with `peer` or run a handshake to establish one
+    async fn session_with(&self, peer: &str, ip: Ipv4Addr, port: u16) -> Result<Session, String> {
+        if let Some(session) = self.sessions.get(peer).await {
+            return Ok(session);
+        }
+        let (handshake, mut established) = self.sessions.begin(peer).await;
+        let data = self.envelope(handshake).encode()
+            .map_err(|e| format!("Failed to serialize handshake: {}", e))?;
+        let mut wait = HANDSHAKE_TIMEOUT;
+        for _ in 0..HANDSHAKE_ATTEMPTS {
+            if let Err(e) = self.sockets.send_message(ip, port, &data).await {
+                warn!("Failed to send handshake to {}: {}", peer, e);
+            }
+            if let Ok(Ok(session)) = time::timeout(wait, &mut established).await {
+                info!("Encrypted session {} established with {}", session.id, peer);
+                return Ok(session);
+            }
+            wait *= 2;
+        }
+        self.sessions.abandon(peer).await;
+        Err(format!("Could not establish an encrypted session with {}: no handshake reply", peer))
+    }
+
+    fn seal_chat(&self, session: &Session, peer: &str, id: &str, plaintext: &[u8]) -> Result<Vec<Vec<u8>>, String> {
+        let (nonce, ciphertext) = session.seal(&chat_aad(&self.uuid, peer, id), plaintext)?;
+        let chunks = fragment::split(&ciphertext, FRAGMENT_CONTENT_SIZE);
+        let total = u16::try_from(chunks.len())
+            .map_err(|_| "Message has too many fragments".to_string())?;
+        let payloads: Vec<Payload> = if total == 1 {
+            vec![Payload::Chat { id: id.to_string(), session: session.id.clone(), nonce, ciphertext }]
+        } else {
+            chunks.into_iter().enumerate()
+                .map(|(index, ciphertext)| Payload::Fragment {
+                    id: id.to_string(),
+                    index: index as u16,
+                    total,
+                    session: session.id.clone(),
+                    nonce: nonce.clone(),
+                    ciphertext,
+                })
+                .collect()
+        };
+        payloads.into_iter()
+            .map(|payload| self.envelope(payload).encode())
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| format!("Failed to serialize message: {}", e))
+    }
+
+    async fn deliver_chat(&self, peer: &str, ip: Ipv4Addr, port: u16, id: &str, plaintext: &[u8]) -> DeliveryStatus {
+        // 对方丢失会话（例如重启）时重新握手一次
+        for _ in 0..2 {
+            let result = match self.session_with(peer, ip, port).await {
+                Ok(session) => self.seal_chat(&session, peer, id, plaintext),
+                Err(e) => Err(e),
+            };
+            let datagrams = match result {
+                Ok(datagrams) => datagrams,
+                Err(reason) => return DeliveryStatus::Aborted { reason },
+            };
+            match self.acks.deliver(&self.sockets, id, ip, port, &datagrams).await {
+                DeliveryStatus::SessionLost => {
+                    warn!("{} lost our session, establishing a new one", peer);
+                    self.sessions.forget(peer).await;
+                },
+                status => return status,
+            }
+        }
+        DeliveryStatus::Aborted { reason: format!("{} keeps rejecting our encrypted session", peer) }
+    }
+}
+
+// The event reporting how delivering a message to `recipient` ended. A queued
+// message that failed stays in the queue.
+fn delivery_event(status: &DeliveryStatus, recipient: &str, conversation: Conversation, pending_id: Option<u64>) -> Event {
+    let recipient = recipient.to_string();
+    let reason = match status {
+        DeliveryStatus::Delivered { attempts } => {
+            return Event::Delivered { recipient, conversation, pending_id, attempts: *attempts };
+        },
+        DeliveryStatus::Failed { attempts } => format!("not acknowledged after {} attempts", attempts),
+        DeliveryStatus::SessionLost => "encrypted session lost".to_string(),
+        DeliveryStatus::Aborted { reason } => reason.clone(),
+    };
+    Event::DeliveryFailed { recipient, conversation, pending_id, reason, requeued: pending_id.is_some() }
+}
+
+fn context(e: io::Error, what: &str) -> io::Error {
+    io::Error::new(e.kind(), format!("{}: {}", what, e))
+}
+
+// Delivers the pending queue of one peer in order. A message leaves the queue
+// only once it is acknowledged, so nothing queued later can overtake it.
+struct PendingFlush {
+    uuid: String,
+    outbound: Outbound,
+    nodes: Arc<Mutex<HashMap<String, NodeInfo>>>,
+    pending: Arc<Mutex<PendingQueue>>,
+    flushing: Arc<Mutex<HashSet<String>>>,
+    events: broadcast::Sender<Event>,
+    max_message_size: usize,
+}
+
+impl PendingFlush {
+    async fn run(self) {
+        loop {
+            let message = {
+                let pending = self.pending.lock().await;
+                match pending.next_for(&self.uuid) {
+                    Some(message) => message,
+                    None => {
+                        self.flushing.lock().await.remove(&self.uuid);
+                        return;
+                    }
+                }
+            };
+            // 对方又下线了，剩下的消息等它下次上线
+            let Some(node) = self.nodes.lock().await.get(&self.uuid).cloned() else {
+                break;
+            };
+            let plaintext = match chat_plaintext(&message.text, Scope::Direct, self.max_message_size) {
+                Ok(plaintext) => plaintext,
+                Err(reason) => {
+                    self.remove(message.id).await;
+                    let _ = self.events.send(Event::DeliveryFailed {
+                        conversation: Conversation::Peer(message.recipient.clone()),
+                        recipient: message.recipient,
+                        pending_id: Some(message.id),
+                        reason,
+                        requeued: false,
+                    });
+                    continue;
+                }
+            };
+            let id = Uuid::new_v4().to_string();
+            info!("Sending queued message #{} as {} to UUID: {} at {}:{}", message.id, id, self.uuid, node.ip, node.port);
+            let status = self.outbound.deliver_chat(&self.uuid, node.ip, node.port, &id, &plaintext).await;
+            let delivered = matches!(status, DeliveryStatus::Delivered { .. });
+            if delivered {
+                self.remove(message.id).await;
+            }
+            let conversation = Conversation::Peer(message.recipient.clone());
+            let _ = self.events.send(delivery_event(&status, &message.recipient, conversation, Some(message.id)));
+            // 失败的消息留在队列开头，下次公告时重试
+            if !delivered {
+                break;
+            }
+        }
+        self.flushing.lock().await.remove(&self.uuid);
+    }
+
+    async fn remove(&self, id: u64) {
+        // 投递期间用户可能已经取消了这条消息
+        if let Err(e) = self.pending.lock().await.cancel(id) {
+            info!("{}", e);
         }
     }
 }
@@ -41,130 +298,971 @@ impl NodeInfo {
 // Utility functions to manage nodes in a thread-safe manner
 pub struct NodeManager {
     pub nodes: Arc<Mutex<HashMap<String, NodeInfo>>>,
-    pub ip: Ipv4Addr,
-    pub port: u16,
     pub uuid: String,
+    // Largest chat message accepted or sent, in bytes
+    pub max_message_size: usize,
+    // A node not heard from for this long is removed
+    pub offline_timeout: Duration,
+    acks: AckTracker,
+    sessions: Sessions,
+    outbound: Outbound,
+    reassembler: Arc<Mutex<Reassembler>>,
+    profile: Profile,
+    // Saved aliases by UUID, including peers that are currently offline
+    aliases: Arc<Mutex<HashMap<String, String>>>,
+    identity: Arc<Identity>,
+    known_peers: Arc<Mutex<KnownPeers>>,
+    rooms: Arc<Mutex<Rooms>>,
+    // Direct messages waiting for offline peers
+    pending: Arc<Mutex<PendingQueue>>,
+    // Peers whose queued messages are being delivered
+    flushing: Arc<Mutex<HashSet<String>>>,
+    // Timestamp of the newest presence message accepted per node and signed address
+    presence_times: Arc<Mutex<HashMap<(String, Ipv4Addr), u64>>>,
+    // Peers already reported for signing with a clock too far from ours
+    clock_skewed: Arc<Mutex<HashSet<String>>>,
+    // Sent and received messages, when history is enabled
+    history: Option<Arc<Mutex<History>>>,
+    // Group that private room traffic is sent to
+    multicast_addr: SocketAddrV4,
+    // What multicast_sender announces about us; rooms change it at runtime
+    presence: Arc<Mutex<PresenceInfo>>,
+    events: broadcast::Sender<Event>,
 }
 
 impl NodeManager {
-    pub fn new(ip: Ipv4Addr, port: u16, uuid: String) -> Self {
-        NodeManager {
+    // Fails when a file in the profile directory cannot be read
+    pub fn new(ip: Ipv4Addr, port: u16, sockets: ChatSockets, identity: Arc<Identity>, profile: Profile, config: &Config) -> io::Result<Self> {
+        let uuid = identity.node_id();
+        let acks = AckTracker::new();
+        let sessions = Sessions::new(Arc::clone(&identity));
+        let outbound = Outbound { uuid: uuid.clone(), ip, port, acks: acks.clone(), sessions: sessions.clone(), sockets };
+        let aliases = profile.load_aliases().unwrap_or_else(|e| {
+            warn!("Failed to load saved aliases: {}", e);
+            HashMap::new()
+        });
+        let known_peers = KnownPeers::load(&profile).map_err(|e| context(e, "Failed to load known peers"))?;
+        let rooms = Rooms::load(&profile).map_err(|e| context(e, "Failed to load rooms"))?;
+        // 未开启历史记录时排队的消息只留在内存中，不写入磁盘
+        let pending = if config.history {
+            PendingQueue::load(&profile).map_err(|e| context(e, "Failed to load pending messages"))?
+        } else {
+            PendingQueue::in_memory()
+        };
+        let history = if config.history {
+            let history = History::open(&profile, config.history_retention())
+                .map_err(|e| io::Error::other(format!("Failed to open message history: {}", e)))?;
+            Some(Arc::new(Mutex::new(history)))
+        } else {
+            None
+        };
+        let presence = PresenceInfo {
+            display_name: config.display_name.clone(),
+            status: config.status.clone(),
+            rooms: rooms.public_rooms(),
+            private_rooms: rooms.private_rooms(),
+        };
+        Ok(NodeManager {
             nodes: Arc::new(Mutex::new(HashMap::new())),
-            ip,
-            port,
             uuid,
-        }
+            max_message_size: config.max_message_size,
+            offline_timeout: config.offline_timeout(),
+            acks,
+            sessions,
+            outbound,
+            reassembler: Arc::new(Mutex::new(Reassembler::new())),
+            profile,
+            aliases: Arc::new(Mutex::new(aliases)),
+            identity,
+            known_peers: Arc::new(Mutex::new(known_peers)),
+            rooms: Arc::new(Mutex::new(rooms)),
+            pending: Arc::new(Mutex::new(pending)),
+            flushing: Arc::default(),
+            presence_times: Arc::default(),
+            clock_skewed: Arc::default(),
+            history,
+            multicast_addr: config.multicast_addr.parse().expect("multicast_addr is validated on start"),
+            presence: Arc::new(Mutex::new(presence)),
+            events: events::channel(),
+        })
+    }
+
+    // ChatNodeBuilder::build subscribes before the node starts, so the first
+    // stream misses nothing
+    pub fn subscribe(&self) -> broadcast::Receiver<Event> {
+        self.events.subscribe()
+    }
+
+    // Nobody listening, e.g. in headless mode before a frontend attached, is fine
+    pub fn publish(&self, event: Event) {
+        let _ = self.events.send(event);
     }
 
-    pub async fn process_message(&self, message_data: Vec<u8>) {
-        match serde_json::from_slice::<Message>(&message_data) {
-            Ok(message) => {
-                println!(
-                    "Received Message: IP = {}, Port = {}, UUID = {}, Content = {}",
-                    message.ip, message.port, message.name, message.content
-                );
+    // Shared with multicast_sender so changes show up in the next announcement
+    pub fn presence(&self) -> Arc<Mutex<PresenceInfo>> {
+        Arc::clone(&self.presence)
+    }
 
-                self.add_or_update_node(message.name.clone(), message.ip, message.port).await;
+    // Build an envelope carrying this node's identity and address
+    pub fn envelope(&self, payload: Payload) -> Envelope {
+        self.outbound.envelope(payload)
+    }
 
+    pub async fn process_message(&self, message_data: Vec<u8>, src: SocketAddr) {
+        let envelope = match Envelope::decode(&message_data) {
+            Ok(envelope) => envelope,
+            Err(DecodeError::Malformed(e)) => {
+                warn!("Failed to deserialize message from {}: {}", src, e);
+                return;
             },
             Err(e) => {
-                println!("Failed to deserialize message: {}", e);
+                warn!("Dropping datagram: {}", e);
+                return;
             }
+        };
+
+        // 节点表只接受经过签名验证的组播公告，这里不更新
+        let Envelope { sender, ip, port, payload, .. } = envelope;
+        // 回复发往数据报的真实来源地址，端口使用对方声明的监听端口
+        let ip = network::observed_ip(src, ip);
+
+        match payload {
+            Payload::Chat { id, session, nonce, ciphertext } => {
+                self.open_chat(&sender, ip, port, id, &session, &nonce, &ciphertext).await;
+            },
+            Payload::Fragment { id, index, total, session, nonce, ciphertext } => {
+                // 已经完整收到的消息被重传时只需再次回复 Ack
+                if self.acks.has_seen(&id).await {
+                    self.reply(&sender, ip, port, Payload::Ack { id }).await;
+                    return;
+                }
+                // 只为当前会话缓存分片，未经认证的数据报不能占用内存
+                match self.sessions.find(&sender, &session).await {
+                    Some(_) => {},
+                    None => {
+                        self.reply(&sender, ip, port, Payload::SessionUnknown { id }).await;
+                        return;
+                    }
+                }
+                let complete = {
+                    let mut reassembler = self.reassembler.lock().await;
+                    for (expired, from) in reassembler.prune(REASSEMBLY_TIMEOUT) {
+                        warn!("Dropped incomplete message {} from {}", expired, from);
+                    }
+                    let max_sealed = session::sealed_len(self.max_message_size);
+                    reassembler.insert(&sender, &id, index, total, ciphertext, max_sealed)
+                };
+                match complete {
+                    Ok(Some(ciphertext)) => self.open_chat(&sender, ip, port, id, &session, &nonce, &ciphertext).await,
+                    Ok(None) => {},
+                    Err(e) => warn!("Dropping fragment from {}: {}", sender, e),
+                }
+            },
+            Payload::Ack { id } => {
+                if !self.acks.acknowledge(&id).await {
+                    info!("Ignoring late or unknown ack {} from {}", id, sender);
+                }
+            },
+            Payload::SessionUnknown { id } => {
+                self.acks.session_unknown(&id).await;
+            },
+            Payload::Handshake { public_key, ephemeral, timestamp, signature } => {
+                let peer_key = match self.handshake_key(&sender, &public_key).await {
+                    Ok(key) => key,
+                    Err(e) => {
+                        warn!("Rejected handshake from {}: {}", sender, e);
+                        return;
+                    }
+                };
+                match self.sessions.accept(&sender, &peer_key, &ephemeral, timestamp, &signature).await {
+                    Ok(Some(reply)) => self.reply(&sender, ip, port, reply).await,
+                    Ok(None) => {},
+                    Err(e) => {
+                        warn!("Rejected handshake from {}: {}", sender, e);
+                        // 签名有效但时间戳超出范围，多半是双方时钟不一致
+                        if check_timestamp(timestamp).is_err() && self.sessions.signed_by(&sender, &peer_key, &ephemeral, timestamp, &signature) {
+                            self.report_clock_skew(&sender, timestamp).await;
+                        }
+                    },
+                }
+            },
+            Payload::HandshakeReply { ephemeral, signature } => {
+                let Some(peer_key) = self.nodes.lock().await.get(&sender).map(|node| node.public_key) else {
+                    warn!("Ignoring handshake reply from unknown peer {}", sender);
+                    return;
+                };
+                if let Err(e) = self.sessions.complete(&sender, &peer_key, &ephemeral, &signature).await {
+                    warn!("Rejected handshake reply from {}: {}", sender, e);
+                }
+            },
+            Payload::Ping => {
+                self.reply(&sender, ip, port, Payload::Pong).await;
+            },
+            Payload::Pong => {
+                self.publish(Event::Pong { uuid: sender });
+            },
+            // Presence and private room traffic belong to the multicast group, not the chat socket
+            other @ (Payload::Announce { .. } | Payload::Goodbye { .. } | Payload::RoomChat { .. }) => {
+                warn!("Unexpected {:?} from {} on chat socket", other, sender);
+            },
         }
     }
 
-    pub async fn list_users(&self) -> Vec<String> {
+    // The key a handshake claims must be the one the sender's UUID derives from,
+    // and the one we already know if the sender has announced itself. Only
+    // announced or known peers may set up a session, so made-up keys cannot
+    // fill the session table.
+    async fn handshake_key(&self, sender: &str, public_key: &str) -> Result<VerifyingKey, String> {
+        let key = identity::parse_public_key(public_key)?;
+        if identity::node_id_for(&key) != sender {
+            return Err("node ID is not derived from the handshake key".to_string());
+        }
+        let announced = match self.nodes.lock().await.get(sender) {
+            Some(node) if node.public_key != key => return Err("key does not match the announced key".to_string()),
+            Some(_) => true,
+            None => false,
+        };
+        self.check_trust(sender, &key, announced).await?;
+        Ok(key)
+    }
+
+    // Trust on first use: record a new peer's key when `record` is set, refuse
+    // one that changed. Without `record` an unknown peer is refused.
+    async fn check_trust(&self, uuid: &str, key: &VerifyingKey, record: bool) -> Result<(), String> {
+        let check = {
+            let mut known_peers = self.known_peers.lock().await;
+            if record {
+                known_peers.check(uuid, key).map_err(|e| format!("Failed to save known peers: {}", e))?
+            } else {
+                known_peers.lookup(uuid, key)
+            }
+        };
+        match check {
+            TrustCheck::New if !record => Err(format!("{} has not announced itself", uuid)),
+            TrustCheck::New => {
+                info!("Recorded key fingerprint {} for {}", identity::fingerprint(key), uuid);
+                Ok(())
+            },
+            TrustCheck::Known => Ok(()),
+            TrustCheck::Mismatch { expected, got } => Err(format!(
+                "\n!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!\n\
+                 !!! KEY CHANGED FOR {}\n\
+                 !!! expected fingerprint: {}\n\
+                 !!! received fingerprint: {}\n\
+                 !!! Someone may be impersonating this peer. It has been ignored.\n\
+                 !!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!",
+                uuid, expected, got
+            )),
+        }
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    async fn open_chat(&self, sender: &str, ip: Ipv4Addr, port: u16, id: String, session: &str, nonce: &str, ciphertext: &str) {
+        if self.acks.has_seen(&id).await {
+            self.reply(sender, ip, port, Payload::Ack { id }).await;
+            return;
+        }
+        let current = match self.sessions.find(sender, session).await {
+            Some(current) => current,
+            // 会话不存在或已过期，通知发送方重新握手
+            None => {
+                self.reply(sender, ip, port, Payload::SessionUnknown { id }).await;
+                return;
+            }
+        };
+        let body = current.open(&chat_aad(sender, &self.uuid, &id), nonce, ciphertext)
+            .and_then(|plaintext| serde_json::from_slice::<ChatBody>(&plaintext).map_err(|e| e.to_string()));
+        if body.is_ok() {
+            // 对方用新会话发来的消息通过了认证，新会话从此取代旧会话
+            self.sessions.confirm(sender, &current.id).await;
+        }
+        match body {
+            Ok(body) if current.first_use(&id) => self.receive_chat(sender, ip, port, id, body).await,
+            // 同一会话中重复出现的消息 ID 是重传或重放，只回复 Ack
+            Ok(_) => {
+                info!("Dropping repeated message {} from {}", id, sender);
+                self.reply(sender, ip, port, Payload::Ack { id }).await;
+            },
+            Err(e) => warn!("Dropping message {} from {}: {}", id, sender, e),
+        }
+    }
+
+    async fn receive_chat(&self, sender: &str, ip: Ipv4Addr, port: u16, id: String, mut body: ChatBody) {
+        // 先过滤控制字符，之后显示、记录和存储的都是过滤后的正文
+        body.text = clean_message(&body.text);
+        // 重传的消息同样需要回复 Ack，但只显示一次
+        if self.acks.first_sighting(&id).await {
+            let conversation = match &body.scope {
+                Scope::Direct => Conversation::Peer(sender.to_string()),
+                Scope::Broadcast => Conversation::Broadcast,
+                Scope::Room { room } => {
+                    let mut rooms = self.rooms.lock().await;
+                    // 私有房间的消息只走组播，不接受单播
+                    if !rooms.is_member(room) || rooms.key(room).is_some() {
+                        // 已经离开的房间，仍然回复 Ack 以免对方重传
+                        warn!("Ignoring message {} from {} for room {} we have not joined", id, sender, room);
+                        drop(rooms);
+                        self.reply(sender, ip, port, Payload::Ack { id }).await;
+                        return;
+                    }
+                    rooms.record(room, sender, &body.text);
+                    Conversation::Room(room.clone())
+                },
+            };
+            let stored = match &body.scope {
+                Scope::Room { room } => history::room_conversation(room),
+                _ => sender.to_string(),
+            };
+            self.remember(&id, &stored, sender, &body.scope, &body.text).await;
+            self.publish(Event::MessageReceived {
+                conversation,
+                sender: sender.to_string(),
+                ip,
+                port,
+                text: body.text,
+                private: false,
+            });
+        }
+        self.reply(sender, ip, port, Payload::Ack { id }).await;
+    }
+
+    async fn reply(&self, to: &str, ip: Ipv4Addr, port: u16, payload: Payload) {
+        if let Err(e) = self.send_envelope(ip, port, &self.envelope(payload)).await {
+            warn!("Failed to reply to {}: {}", to, e);
+        }
+    }
+
+    async fn send_envelope(&self, ip: Ipv4Addr, port: u16, envelope: &Envelope) -> Result<(), String> {
+        let data = envelope.encode()
+            .map_err(|e| format!("Failed to serialize message: {}", e))?;
+        self.outbound.sockets.send_message(ip, port, &data).await
+            .map_err(|e| format!("Failed to send message: {}", e))
+    }
+
+    // Turn an alias, full UUID, unique UUID prefix or display name into a peer
+    // UUID. Peers seen before but currently offline resolve as well.
+    pub async fn resolve_peer(&self, identifier: &str) -> Result<String, String> {
+        if identifier.is_empty() {
+            return Err("No peer given".to_string());
+        }
         let nodes = self.nodes.lock().await;
+        let aliases = self.aliases.lock().await;
+        let known_peers = self.known_peers.lock().await;
+        // 别名是本地设置的，优先于其他方式
+        if let Some((uuid, _)) = aliases.iter().find(|(_, alias)| alias.as_str() == identifier) {
+            return Ok(uuid.clone());
+        }
+
+        let candidates: BTreeSet<&String> = nodes.keys().chain(known_peers.uuids()).collect();
+        if candidates.contains(&identifier.to_string()) {
+            return Ok(identifier.to_string());
+        }
+        // 对方声明的名称不可信：与 UUID 前缀同时匹配时视为有歧义，
+        // 以免有人把名称设成别人的 UUID 前缀来截获消息
+        let matches: BTreeSet<&str> = candidates.into_iter()
+            .filter(|uuid| uuid.starts_with(identifier))
+            .chain(nodes.iter()
+                .filter(|(_, node)| node.info.display_name.as_deref() == Some(identifier))
+                .map(|(uuid, _)| uuid))
+            .map(String::as_str)
+            .collect();
+        let matches: Vec<&str> = matches.into_iter().collect();
+        match matches.as_slice() {
+            [] => Err(format!("No peer matches {}", identifier)),
+            [uuid] => Ok(uuid.to_string()),
+            _ => Err(format!("{} is ambiguous, it matches {}", identifier, matches.join(", "))),
+        }
+    }
+
+    // Everything resolve_peer accepts, for tab completion: aliases, names of
+    // online peers and the UUIDs of all peers seen
+    pub async fn peer_names(&self) -> Vec<String> {
+        let nodes = self.nodes.lock().await;
+        let aliases = self.aliases.lock().await;
+        let known_peers = self.known_peers.lock().await;
+        let names: BTreeSet<String> = aliases.values().cloned()
+            .chain(nodes.values().filter_map(|node| node.info.display_name.clone()))
+            .chain(nodes.keys().chain(known_peers.uuids()).cloned())
+            .collect();
+        names.into_iter().collect()
+    }
+
+    // Details about one peer, online or not
+    pub async fn peer_info(&self, uuid: &str) -> Result<Vec<String>, String> {
+        let node = self.nodes.lock().await.get(uuid).cloned();
+        let alias = self.aliases.lock().await.get(uuid).cloned();
+        let known = self.known_peers.lock().await.get(uuid).cloned();
+        if node.is_none() && known.is_none() {
+            return Err(format!("UUID {} not found", uuid));
+        }
+
+        let mut lines = vec![format!("UUID: {}", uuid), format!("Alias: {:?}", alias)];
+        match node {
+            Some(node) => {
+                lines.push(format!("Display name: {:?}", node.info.display_name));
+                lines.push(format!("Status: {}", node.info.status.as_deref().unwrap_or("-")));
+                lines.push(format!("Address: {}:{}", node.ip, node.port));
+                lines.push(format!("Last seen: {}s ago", node.last_active.elapsed().as_secs()));
+            },
+            None => lines.push("Status: offline".to_string()),
+        }
+        if let Some(known) = known {
+            lines.push(format!(
+                "Fingerprint: {} ({})",
+                known.fingerprint, if known.verified { "verified" } else { "unverified" }
+            ));
+        }
+        Ok(lines)
+    }
+
+    pub async fn peers(&self) -> Vec<Peer> {
+        let nodes = self.nodes.lock().await;
+        let known_peers = self.known_peers.lock().await;
         nodes.iter()
-            .map(|(uuid, node)| format!("UUID: {}, IP: {}, Port: {}, Alias: {:?}", uuid, node.ip, node.port, node.alias))
+            .map(|(uuid, node)| Peer {
+                uuid: uuid.clone(),
+                alias: node.alias.clone(),
+                display_name: node.info.display_name.clone(),
+                status: node.info.status.clone(),
+                ip: node.ip,
+                port: node.port,
+                verified: known_peers.get(uuid).is_some_and(|peer| peer.verified),
+            })
+            .collect()
+    }
+
+    pub async fn list_users(&self) -> Vec<String> {
+        let peers = self.peers().await;
+        // 统计每个名称被多少个节点使用，用于标记冲突
+        let mut claims: HashMap<&str, usize> = HashMap::new();
+        for peer in &peers {
+            if let Some(name) = peer.name() {
+                *claims.entry(name).or_default() += 1;
+            }
+        }
+        peers.iter()
+            .map(|peer| {
+                let name = match peer.name() {
+                    Some(name) if claims[name] > 1 => format!("{} [duplicate name]", name),
+                    Some(name) => name.to_string(),
+                    None => "-".to_string(),
+                };
+                format!(
+                    "UUID: {}, Name: {}, Status: {}, IP: {}, Port: {}, Alias: {:?}, Key: {}",
+                    peer.uuid, name, peer.status.as_deref().unwrap_or("-"), peer.ip, peer.port, peer.alias,
+                    if peer.verified { "verified" } else { "unverified" }
+                )
+            })
             .collect()
     }
 
-    pub async fn send_message(&self, uuid: &str, content: &str) -> Result<(), String> {
+    // Our own fingerprint, or the recorded fingerprint and verification state of a peer
+    pub async fn fingerprint(&self, uuid: Option<&str>) -> Result<(String, bool), String> {
+        let Some(uuid) = uuid else {
+            return Ok((identity::fingerprint(&self.identity.public_key()), true));
+        };
+        let known_peers = self.known_peers.lock().await;
+        let peer = known_peers.get(uuid).ok_or_else(|| format!("No recorded key for {}", uuid))?;
+        Ok((peer.fingerprint.clone(), peer.verified))
+    }
+
+    pub async fn verify_peer(&self, uuid: &str) -> Result<(), String> {
+        self.known_peers.lock().await.mark_verified(uuid)
+    }
+
+    // Start delivering a message. A known peer that is offline gets it queued
+    // until it comes back.
+    pub async fn send_message(&self, uuid: &str, content: &str) -> Result<SendOutcome, String> {
+        let plaintext = self.chat_plaintext(content, Scope::Direct)?;
+        let node_info = self.nodes.lock().await.get(uuid).cloned();
+        let Some(node_info) = node_info else {
+            if self.known_peers.lock().await.get(uuid).is_none() {
+                return Err(format!("UUID {} not found", uuid));
+            }
+            let id = self.pending.lock().await.push(uuid, content)?;
+            self.remember(&Uuid::new_v4().to_string(), uuid, &self.uuid, &Scope::Direct, content).await;
+            return Ok(SendOutcome::Queued(id));
+        };
+        // 还有排队的消息时排在它们后面，保持发送顺序
+        let behind = {
+            let mut pending = self.pending.lock().await;
+            if pending.has_for(uuid) { Some(pending.push(uuid, content)?) } else { None }
+        };
+        self.remember(&Uuid::new_v4().to_string(), uuid, &self.uuid, &Scope::Direct, content).await;
+        if let Some(id) = behind {
+            self.flush_pending(uuid).await;
+            return Ok(SendOutcome::QueuedBehind(id));
+        }
+        Ok(SendOutcome::Sending(self.deliver(uuid, &node_info, plaintext, Conversation::Peer(uuid.to_string()))))
+    }
+
+    // Deliver what is queued for an online peer, one message after the other.
+    // Runs when the peer comes back, on each later announcement while a failed
+    // delivery is left in the queue, and for messages sent behind queued ones.
+    pub async fn flush_pending(&self, uuid: &str) {
+        {
+            let pending = self.pending.lock().await;
+            // 每个节点同时只有一个投递任务，以保持顺序
+            if !pending.has_for(uuid) || !self.flushing.lock().await.insert(uuid.to_string()) {
+                return;
+            }
+        }
+        let flush = PendingFlush {
+            uuid: uuid.to_string(),
+            outbound: self.outbound.clone(),
+            nodes: Arc::clone(&self.nodes),
+            pending: Arc::clone(&self.pending),
+            flushing: Arc::clone(&self.flushing),
+            events: self.events.clone(),
+            max_message_size: self.max_message_size,
+        };
+        tokio::spawn(flush.run());
+    }
+
+    // Queued messages, oldest first
+    pub async fn list_pending(&self) -> Vec<String> {
+        let pending = self.pending.lock().await;
         let nodes = self.nodes.lock().await;
-        if let Some(node_info) = nodes.get(uuid) {
-            // 构建 Message 结构体
-            let message = Message {
-                ip: self.ip,
-                port: self.port,
-                name: self.uuid.clone(),
-                content: content.to_string(),
-            };
+        let aliases = self.aliases.lock().await;
+        pending.list().iter()
+            .map(|message| {
+                let recipient = aliases.get(&message.recipient)
+                    .map(String::as_str)
+                    .or_else(|| nodes.get(&message.recipient).and_then(NodeInfo::name))
+                    .unwrap_or(&message.recipient);
+                format!(
+                    "#{} [{}] to {}: {}",
+                    message.id, rooms::format_timestamp(message.queued_at), recipient, message.text
+                )
+            })
+            .collect()
+    }
 
-            // 将 Message 序列化为 JSON
-            let serialized_message = serde_json::to_string(&message)
-                .map_err(|e| format!("Failed to serialize message: {}", e))?;
+    pub async fn cancel_pending(&self, id: u64) -> Result<(), String> {
+        self.pending.lock().await.cancel(id).map(|_| ())
+    }
 
-            // 调用发送 UDP 消息的函数
-            match udp_connection::send_message(node_info.ip, node_info.port, &serialized_message).await {
-                Ok(_) => {
-                    println!("Message '{}' sent to UUID: {} at {}:{}", content, uuid, node_info.ip, node_info.port);
-                    Ok(())
-                },
-                Err(e) => Err(format!("Failed to send message: {}", e)),
+    // Send a copy of the message to every online peer, each over its own session
+    pub async fn broadcast(&self, content: &str) -> Result<Vec<(String, JoinHandle<DeliveryStatus>)>, String> {
+        let plaintext = self.chat_plaintext(content, Scope::Broadcast)?;
+        let nodes = self.nodes.lock().await.clone();
+        if nodes.is_empty() {
+            return Err("No peers online".to_string());
+        }
+        self.remember(&Uuid::new_v4().to_string(), history::BROADCAST_CONVERSATION, &self.uuid, &Scope::Broadcast, content).await;
+        Ok(nodes.iter()
+            .map(|(uuid, node_info)| (uuid.clone(), self.deliver(uuid, node_info, plaintext.clone(), Conversation::Broadcast)))
+            .collect())
+    }
+
+    // Join (or create) a room; returns false if already a member. With a key
+    // derived from a passphrase the room is private and its traffic is
+    // encrypted with that key.
+    pub async fn join_room(&self, room: &str, key: Option<RoomKey>) -> Result<bool, String> {
+        let mut rooms = self.rooms.lock().await;
+        let joined = match key {
+            Some(key) => {
+                rooms::check_room_name(room)?;
+                // 在线成员使用的密钥都与我们的不同，说明口令错误
+                let nodes = self.nodes.lock().await;
+                let mut advertised = nodes.values().filter_map(|node| node.info.private_rooms.get(room)).peekable();
+                if advertised.peek().is_some() && !advertised.any(|key_id| key_id == key.id()) {
+                    return Err(format!("Wrong passphrase for room {}", room));
+                }
+                drop(nodes);
+                rooms.join_private(room, key)?
+            },
+            None => rooms.join(room)?,
+        };
+        self.advertise_rooms(&rooms).await;
+        Ok(joined)
+    }
+
+    pub async fn leave_room(&self, room: &str) -> Result<bool, String> {
+        let mut rooms = self.rooms.lock().await;
+        let left = rooms.leave(room)?;
+        self.advertise_rooms(&rooms).await;
+        Ok(left)
+    }
+
+    async fn advertise_rooms(&self, rooms: &Rooms) {
+        let mut presence = self.presence.lock().await;
+        presence.rooms = rooms.public_rooms();
+        presence.private_rooms = rooms.private_rooms();
+    }
+
+    // Names of the rooms we are in, open and private alike
+    pub async fn joined_rooms(&self) -> Vec<String> {
+        let rooms = self.rooms.lock().await;
+        let mut joined = rooms.public_rooms();
+        joined.extend(rooms.private_rooms().into_keys());
+        joined.sort();
+        joined
+    }
+
+    // Rooms we joined or that online peers advertise, with their online members
+    pub async fn list_rooms(&self) -> Vec<String> {
+        let rooms = self.rooms.lock().await;
+        let nodes = self.nodes.lock().await;
+        let private = rooms.private_rooms();
+        let mut members: BTreeMap<String, Vec<String>> = rooms.public_rooms().into_iter()
+            .chain(private.keys().cloned())
+            .map(|room| (room, Vec::new()))
+            .collect();
+        for (uuid, node) in nodes.iter() {
+            let name = node.name().unwrap_or(uuid).to_string();
+            for room in &node.info.rooms {
+                if !private.contains_key(room) {
+                    members.entry(room.clone()).or_default().push(name.clone());
+                }
             }
-        } else {
-            Err(format!("UUID {} not found", uuid))
+            // 私有房间只统计与我们使用同一密钥的成员
+            for (room, key_id) in &node.info.private_rooms {
+                if private.get(room).is_none_or(|ours| ours == key_id) {
+                    members.entry(room.clone()).or_default().push(name.clone());
+                }
+            }
+        }
+        members.into_iter()
+            .map(|(room, members)| format!(
+                "{}{}{}: {} online member(s){}{}",
+                if rooms.is_member(&room) { "* " } else { "  " },
+                room,
+                if private.contains_key(&room) { " (private)" } else { "" },
+                members.len(), if members.is_empty() { "" } else { " " }, members.join(", ")
+            ))
+            .collect()
+    }
+
+    // Send a message to the members of a room we have joined: one delivery per
+    // online member for open rooms, one sealed datagram to the multicast group
+    // for private ones
+    pub async fn say(&self, room: &str, content: &str) -> Result<RoomDelivery, String> {
+        rooms::check_room_name(room)?;
+        let plaintext = self.chat_plaintext(content, Scope::Room { room: room.to_string() })?;
+        let key = {
+            let rooms = self.rooms.lock().await;
+            if !rooms.is_member(room) {
+                return Err(format!("Not a member of room {}, join it first", room));
+            }
+            rooms.key(room).cloned()
+        };
+        // 私有房间的消息可能因为过大而发送失败，只有发出去之后才记入历史
+        if let Some(key) = key {
+            self.send_room_chat(room, &key, &plaintext).await?;
+            self.record_said(room, content).await;
+            return Ok(RoomDelivery::Multicast);
+        }
+        self.record_said(room, content).await;
+
+        let nodes = self.nodes.lock().await.clone();
+        Ok(RoomDelivery::Peers(nodes.iter()
+            .filter(|(_, node_info)| node_info.info.rooms.iter().any(|r| r == room))
+            .map(|(uuid, node_info)| {
+                (uuid.clone(), self.deliver(uuid, node_info, plaintext.clone(), Conversation::Room(room.to_string())))
+            })
+            .collect()))
+    }
+
+    // Add a message we sent to the room log and the history store
+    async fn record_said(&self, room: &str, content: &str) {
+        self.rooms.lock().await.record(room, &self.uuid, content);
+        let scope = Scope::Room { room: room.to_string() };
+        self.remember(&Uuid::new_v4().to_string(), &history::room_conversation(room), &self.uuid, &scope, content).await;
+    }
+
+    async fn send_room_chat(&self, room: &str, key: &RoomKey, plaintext: &[u8]) -> Result<(), String> {
+        if session::sealed_len(plaintext.len()) > MAX_ROOM_CIPHERTEXT {
+            return Err(format!("Messages to private rooms are limited to {} bytes", MAX_ROOM_CIPHERTEXT / 4 * 3 - 16));
+        }
+        let id = Uuid::new_v4().to_string();
+        let timestamp = unix_time();
+        let (nonce, ciphertext) = key.seal(&room_aad(room, key.id(), &self.uuid, &id), plaintext)?;
+        let signature = self.identity.sign_hex(&room_signing_bytes(&self.uuid, &id, room, key.id(), timestamp, &nonce, &ciphertext));
+        let envelope = self.envelope(Payload::RoomChat {
+            id,
+            room: room.to_string(),
+            key_id: key.id().to_string(),
+            timestamp,
+            nonce,
+            ciphertext,
+            signature,
+        });
+        self.send_envelope(*self.multicast_addr.ip(), self.multicast_addr.port(), &envelope).await
+    }
+
+    // Handle a RoomChat heard on the multicast group. Messages for rooms we are
+    // not in, or sealed with another passphrase, are dropped unread.
+    pub async fn receive_room_chat(&self, envelope: &Envelope) {
+        let Payload::RoomChat { id, room, key_id, timestamp, nonce, ciphertext, signature } = &envelope.payload else {
+            return;
+        };
+        let sender = &envelope.sender;
+        let Some(node) = self.nodes.lock().await.get(sender).cloned() else {
+            info!("Ignoring room message from unknown node {}", sender);
+            return;
+        };
+        // 时间戳超出范围的消息可能是被截获后重放的，去重记录只覆盖这个范围
+        let data = room_signing_bytes(sender, id, room, key_id, *timestamp, nonce, ciphertext);
+        if let Err(e) = check_timestamp(*timestamp).and_then(|_| identity::verify(&node.public_key, &data, signature)) {
+            warn!("Rejected room message {} claiming to be from {}: {}", id, sender, e);
+            return;
+        }
+
+        let mut rooms = self.rooms.lock().await;
+        let Some(key) = rooms.key(room).cloned() else {
+            return;
+        };
+        if key.id() != key_id {
+            warn!("Cannot read message {} for room {} from {}: it uses a different passphrase", id, room, sender);
+            return;
+        }
+        let body = key.open(&room_aad(room, key_id, sender, id), nonce, ciphertext)
+            .and_then(|plaintext| serde_json::from_slice::<ChatBody>(&plaintext).map_err(|e| e.to_string()));
+        let text = match body {
+            Ok(ChatBody { text, scope: Scope::Room { room: scoped } }) if scoped == *room => clean_message(&text),
+            Ok(_) => {
+                warn!("Dropping message {} from {}: not addressed to room {}", id, sender, room);
+                return;
+            },
+            Err(e) => {
+                warn!("Dropping message {} for room {} from {}: {}", id, room, sender, e);
+                return;
+            },
+        };
+        if self.acks.first_sighting(id).await {
+            rooms.record(room, sender, &text);
+            let scope = Scope::Room { room: room.to_string() };
+            self.remember(id, &history::room_conversation(room), sender, &scope, &text).await;
+            self.publish(Event::MessageReceived {
+                conversation: Conversation::Room(room.clone()),
+                sender: sender.to_string(),
+                ip: node.ip,
+                port: node.port,
+                text,
+                private: true,
+            });
+        }
+    }
+
+    // The last `count` messages of a room: from the history store when it is
+    // enabled, otherwise those seen since start
+    pub async fn room_history(&self, room: &str, count: usize) -> Result<Vec<String>, String> {
+        let rooms = self.rooms.lock().await;
+        if !rooms.is_member(room) {
+            return Err(format!("Not a member of room {}", room));
+        }
+        let messages: Vec<(u64, String, String)> = match &self.history {
+            Some(history) => history.lock().await.recent(&history::room_conversation(room), count)?
+                .into_iter()
+                .map(|message| (message.timestamp, message.sender, message.text))
+                .collect(),
+            None => rooms.history(room, count).into_iter()
+                .map(|message| (message.timestamp, message.sender, message.text))
+                .collect(),
+        };
+        let nodes = self.nodes.lock().await;
+        Ok(messages.into_iter()
+            .map(|(timestamp, sender, text)| {
+                format!("[{}] {}: {}", rooms::format_timestamp(timestamp), self.sender_label(&nodes, &sender), text)
+            })
+            .collect())
+    }
+
+    // Stored messages exchanged with a peer, including its broadcasts
+    pub async fn history(&self, uuid: &str, count: usize) -> Result<Vec<String>, String> {
+        let Some(history) = &self.history else {
+            return Err("History is off; set `history = true` in the config or pass --history".to_string());
+        };
+        let messages = history.lock().await.recent(uuid, count)?;
+        let nodes = self.nodes.lock().await;
+        Ok(messages.into_iter()
+            .map(|message| format!(
+                "[{}] {}{}: {}",
+                rooms::format_timestamp(message.timestamp),
+                self.sender_label(&nodes, &message.sender),
+                if message.kind == "broadcast" { " (broadcast)" } else { "" },
+                message.text
+            ))
+            .collect())
+    }
+
+    // Full-text search over stored messages
+    pub async fn search(&self, query: &SearchQuery) -> Result<Vec<String>, String> {
+        let Some(history) = &self.history else {
+            return Err("History is off; set `history = true` in the config or pass --history".to_string());
+        };
+        let hits = history.lock().await.search(query)?;
+        let nodes = self.nodes.lock().await;
+        Ok(hits.into_iter()
+            .map(|hit| {
+                let place = if hit.conversation.starts_with('#') {
+                    hit.conversation.clone()
+                } else if hit.conversation == history::BROADCAST_CONVERSATION {
+                    "broadcast".to_string()
+                } else {
+                    format!("with {}", self.sender_label(&nodes, &hit.conversation))
+                };
+                format!(
+                    "[{}] {} {}: {}",
+                    rooms::format_timestamp(hit.timestamp), place, self.sender_label(&nodes, &hit.sender), hit.snippet
+                )
+            })
+            .collect())
+    }
+
+    fn sender_label(&self, nodes: &HashMap<String, NodeInfo>, sender: &str) -> String {
+        if sender == self.uuid {
+            return "me".to_string();
         }
+        nodes.get(sender).and_then(NodeInfo::name).unwrap_or(sender).to_string()
     }
-    
 
-    // Asynchronously add a node
-    pub async fn add_or_update_node(&self, uuid: String, ip: Ipv4Addr, port: u16) -> Result<bool, String> {
+    // Store a message when history is enabled; a failure only costs the record
+    async fn remember(&self, message_id: &str, conversation: &str, sender: &str, scope: &Scope, text: &str) {
+        let Some(history) = &self.history else {
+            return;
+        };
+        let (kind, room) = match scope {
+            Scope::Direct => ("direct", None),
+            Scope::Broadcast => ("broadcast", None),
+            Scope::Room { room } => ("room", Some(room.as_str())),
+        };
+        if let Err(e) = history.lock().await.record(message_id, conversation, sender, kind, room, text) {
+            warn!("{}", e);
+        }
+    }
+
+    fn chat_plaintext(&self, content: &str, scope: Scope) -> Result<Vec<u8>, String> {
+        chat_plaintext(content, scope, self.max_message_size)
+    }
+
+    // Deliver in the background; the outcome is published as an event and
+    // returned through the handle
+    fn deliver(&self, uuid: &str, node_info: &NodeInfo, plaintext: Vec<u8>, conversation: Conversation) -> JoinHandle<DeliveryStatus> {
+        let id = Uuid::new_v4().to_string();
+        info!("Sending message {} to UUID: {} at {}:{}", id, uuid, node_info.ip, node_info.port);
+        let outbound = self.outbound.clone();
+        let events = self.events.clone();
+        let peer = uuid.to_string();
+        let (ip, port) = (node_info.ip, node_info.port);
+        tokio::spawn(async move {
+            let status = outbound.deliver_chat(&peer, ip, port, &id, &plaintext).await;
+            let _ = events.send(delivery_event(&status, &peer, conversation, None));
+            status
+        })
+    }
+
+    pub async fn ping(&self, uuid: &str) -> Result<(), String> {
+        let node_info = self.nodes.lock().await.get(uuid).cloned()
+            .ok_or_else(|| format!("UUID {} not found", uuid))?;
+        self.send_envelope(node_info.ip, node_info.port, &self.envelope(Payload::Ping)).await
+    }
+
+    // Asynchronously add a node whose announcement has been verified.
+    // A known UUID showing up with a different key is refused.
+    pub async fn add_or_update_node(&self, uuid: String, ip: Ipv4Addr, port: u16, public_key: VerifyingKey, info: PresenceInfo) -> Result<bool, String> {
+        self.check_trust(&uuid, &public_key, true).await?;
         let mut nodes = self.nodes.lock().await;
-        let node = NodeInfo::new(ip, port);
-        match nodes.entry(uuid.clone()) {
+        match nodes.entry(uuid) {
             std::collections::hash_map::Entry::Vacant(e) => {
-                // 如果 UUID 不存在，则插入新节点
+                // 如果 UUID 不存在，则插入新节点，并恢复之前保存的别名
+                let mut node = NodeInfo::new(ip, port, public_key, info);
+                node.alias = self.aliases.lock().await.get(e.key()).cloned();
+                self.sessions.forget(e.key()).await;
                 e.insert(node);
                 Ok(true) // 返回 true 表示这是一个新节点
             },
             std::collections::hash_map::Entry::Occupied(mut e) => {
-                // 如果 UUID 已存在，更新 last_active 时间并保留其他信息
-                e.get_mut().last_active = Instant::now(); // 假设 NodeInfo 中有 last_active 字段
+                if e.get().public_key != public_key {
+                    return Err(format!("Node {} announced a key that does not match the one already known", e.key()));
+                }
+                // 地址以最新的公告为准。节点重启后 UUID 不变但端口会变，旧会话随之作废；
+                // 只有 IP 变化可能是同一进程从多个网卡发出的公告，会话仍然有效
+                if e.get().port != port {
+                    info!("Node {} moved from {}:{} to {}:{}", e.key(), e.get().ip, e.get().port, ip, port);
+                    self.sessions.forget(e.key()).await;
+                }
+                e.get_mut().ip = ip;
+                e.get_mut().port = port;
+                // 更新 last_active 时间和对方声明的名称，保留别名
+                e.get_mut().last_active = Instant::now();
+                e.get_mut().info = info;
                 Ok(false) // 返回 false 表示这是一个更新的老节点
             }
         }
     }
-    
 
+    // Record the timestamp of a signed presence message. Returns false for one
+    // that is not newer than the last accepted from the same node and address,
+    // i.e. a replay. Each interface of a node announces with its own address, so
+    // they are tracked apart; a goodbye may share the second of the announcement
+    // before it.
+    pub async fn accept_presence(&self, uuid: &str, ip: Ipv4Addr, timestamp: u64, goodbye: bool) -> bool {
+        let mut times = self.presence_times.lock().await;
+        let last = times.entry((uuid.to_string(), ip)).or_insert(0);
+        let fresh = if goodbye { timestamp >= *last } else { timestamp > *last };
+        if fresh {
+            *last = timestamp;
+            // 时钟恢复正常后，再次出现偏差时重新提示
+            self.clock_skewed.lock().await.remove(uuid);
+        }
+        fresh
+    }
 
-    // Asynchronously update a node's alias
+    // Tell the user, once per peer, that a correctly signed message was dropped
+    // because its timestamp is too far from our clock. Without this the peer
+    // just never shows up and the reason is only in the log.
+    pub async fn report_clock_skew(&self, uuid: &str, timestamp: u64) {
+        if !self.clock_skewed.lock().await.insert(uuid.to_string()) {
+            return;
+        }
+        let offset = timestamp as i64 - unix_time() as i64;
+        self.publish(Event::PeerRejected {
+            reason: format!(
+                "Ignoring {}: its clock is {}s {} ours, more than the {}s allowed. Check the clocks of both machines.",
+                uuid, offset.unsigned_abs(), if offset > 0 { "ahead of" } else { "behind" }, MAX_CLOCK_SKEW,
+            ),
+        });
+    }
+
+    // Give a peer a local alias. Offline peers seen before can be named too,
+    // since aliases are kept by UUID.
     pub async fn update_node_alias(&self, uuid: &str, alias: String) -> Result<(), String> {
+        let alias = alias.trim().to_string();
+        if alias.is_empty() || alias.chars().any(char::is_control) {
+            return Err("Alias must be printable text".to_string());
+        }
         let mut nodes = self.nodes.lock().await;
+        let mut aliases = self.aliases.lock().await;
+        let known_peers = self.known_peers.lock().await;
         // Check if the new alias is already in use by another node
-        if nodes.values().any(|node| node.alias.as_ref() == Some(&alias)) {
-            return Err("Alias already exists".to_string());
+        if let Some((other, _)) = aliases.iter().find(|(id, existing)| **existing == alias && id.as_str() != uuid) {
+            return Err(format!("Alias {} is already used for {}", alias, other));
         }
-    
-        if let Some(node) = nodes.get_mut(uuid) {
-            node.alias = Some(alias);  // Update the alias
-            return Ok(());
+        // 别名优先于 UUID 解析，不能用别人的 UUID 作别名
+        if alias != uuid && (nodes.contains_key(&alias) || known_peers.get(&alias).is_some()) {
+            return Err(format!("Alias {} is the UUID of another peer", alias));
         }
-    
-        Err("UUID not found".to_string())
-    }
 
-    // Asynchronously get node information
-    pub async fn get_node_info(&self, uuid: &str) -> Option<NodeInfo> {
-        let nodes = self.nodes.lock().await;
-        nodes.values().find(|node| node.alias.as_ref().map(String::as_str) == Some(uuid))
-            .or_else(|| nodes.get(uuid))
-            .cloned() // Clone the data to release the lock
-    }
-    
-    // Asynchronously remove a node
-    pub async fn remove_node(&self, uuid: String) -> Result<(), String> {
-        let mut nodes = self.nodes.lock().await;
-        if !nodes.contains_key(&uuid) {
-            return Err("UUID does not exist".to_string());
+        match nodes.get_mut(uuid) {
+            Some(node) => node.alias = Some(alias.clone()),
+            None if known_peers.get(uuid).is_none() => return Err("UUID not found".to_string()),
+            None => {},
         }
-        nodes.remove(&uuid);
-        Ok(())
+        aliases.insert(uuid.to_string(), alias);
+        self.profile.save_aliases(&aliases)
+            .map_err(|e| format!("Alias set but could not be saved: {}", e))
     }
 
-     // Asynchronously check and notify offline nodes
-     pub async fn check_and_notify_offline_nodes(&self, notify_tx: &mpsc::Sender<String>) -> Result<(), String> {
+    // Asynchronously remove a node, returning it if it was known
+    pub async fn remove_node(&self, uuid: &str) -> Option<NodeInfo> {
+        self.sessions.forget(uuid).await;
+        self.nodes.lock().await.remove(uuid)
+    }
+
+     // Asynchronously drop nodes not heard from in time and announce them as offline
+     pub async fn check_offline_nodes(&self) {
         let now = Instant::now();
         let mut offline_nodes = Vec::new();
 
@@ -173,20 +1271,127 @@ impl NodeManager {
 
             // Check each node's last active time and collect names of offline nodes
             nodes_locked.retain(|name, node_info| {
-                if now.duration_since(node_info.last_active) > Duration::from_secs(20) {
-                    offline_nodes.push(name.clone());
+                if now.duration_since(node_info.last_active) > self.offline_timeout {
+                    offline_nodes.push((name.clone(), node_info.info.display_name.clone()));
                     false // Remove the node from the map
                 } else {
                     true // Keep the node in the map
                 }
             });
+            // 只为在线节点保留会话
+            self.sessions.retain(|peer| nodes_locked.contains_key(peer)).await;
         }
 
+        // 超出时钟偏差范围的时间戳已经无法通过校验，不必再记住
+        let cutoff = unix_time().saturating_sub(MAX_CLOCK_SKEW);
+        self.presence_times.lock().await.retain(|_, timestamp| *timestamp >= cutoff);
+
         // Send offline notifications for each offline node
-        for name in offline_nodes {
-            notify_tx.send(format!("Node {} went offline!", name)).await.map_err(|e| e.to_string())?;
+        for (name, display_name) in offline_nodes {
+            self.sessions.forget(&name).await;
+            self.publish(Event::PeerOffline { uuid: name, display_name });
         }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::profile::TestDir;
+    use ed25519_dalek::SigningKey;
+    use tokio::net::UdpSocket;
+
+    const ALICE: &str = "aaaa1111-0000-4000-8000-000000000001";
+    const ALBERT: &str = "aaaa2222-0000-4000-8000-000000000002";
+    const BOB: &str = "bbbb1111-0000-4000-8000-000000000003";
+
+    // The directory holds the manager's profile and must outlive it
+    async fn manager() -> (NodeManager, TestDir) {
+        let dir = TestDir::new();
+        let profile = dir.profile();
+        let identity = Arc::new(Identity::load_or_create(&profile).unwrap());
+        let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let port = socket.local_addr().unwrap().port();
+        let manager = NodeManager::new(Ipv4Addr::LOCALHOST, port, ChatSockets::new(vec![(socket, None)]), identity, profile, &Config::default()).unwrap();
+        (manager, dir)
+    }
+
+    fn key(seed: u8) -> VerifyingKey {
+        SigningKey::from_bytes(&[seed; 32]).verifying_key()
+    }
+
+    async fn add_online(manager: &NodeManager, uuid: &str, seed: u8, display_name: Option<&str>) {
+        let info = PresenceInfo { display_name: display_name.map(str::to_string), ..PresenceInfo::default() };
+        manager.nodes.lock().await.insert(uuid.to_string(), NodeInfo::new(Ipv4Addr::LOCALHOST, 9, key(seed), info));
+        manager.known_peers.lock().await.check(uuid, &key(seed)).unwrap();
+    }
+
+    #[tokio::test]
+    async fn resolves_aliases_uuids_prefixes_and_names() {
+        let (manager, _dir) = manager().await;
+        add_online(&manager, ALICE, 1, Some("Alice")).await;
+        add_online(&manager, ALBERT, 2, None).await;
+        // 离线但记录过密钥的节点也能解析
+        manager.known_peers.lock().await.check(BOB, &key(3)).unwrap();
+
+        assert_eq!(manager.resolve_peer(ALICE).await.unwrap(), ALICE);
+        assert_eq!(manager.resolve_peer("aaaa2").await.unwrap(), ALBERT);
+        assert_eq!(manager.resolve_peer("bbbb").await.unwrap(), BOB);
+        assert_eq!(manager.resolve_peer("Alice").await.unwrap(), ALICE);
+        let error = manager.resolve_peer("aaaa").await.unwrap_err();
+        assert!(error.contains("ambiguous") && error.contains(ALICE) && error.contains(ALBERT));
+        assert!(manager.resolve_peer("cccc").await.is_err());
+        assert!(manager.resolve_peer("").await.is_err());
+
+        manager.update_node_alias(BOB, "bob".to_string()).await.unwrap();
+        assert_eq!(manager.resolve_peer("bob").await.unwrap(), BOB);
+        // 别名优先于前缀
+        manager.update_node_alias(ALBERT, "aaaa".to_string()).await.unwrap();
+        assert_eq!(manager.resolve_peer("aaaa").await.unwrap(), ALBERT);
+    }
+
+    #[tokio::test]
+    async fn display_names_never_shadow_uuid_prefixes() {
+        let (manager, _dir) = manager().await;
+        add_online(&manager, ALICE, 1, None).await;
+        add_online(&manager, BOB, 3, Some("aaaa1")).await;
+        add_online(&manager, ALBERT, 2, Some(BOB)).await;
+
+        assert!(manager.resolve_peer("aaaa1").await.unwrap_err().contains("ambiguous"));
+        assert_eq!(manager.resolve_peer(BOB).await.unwrap(), BOB);
+    }
+
+    #[tokio::test]
+    async fn aliases_must_be_unique_and_printable() {
+        let (manager, _dir) = manager().await;
+        add_online(&manager, ALICE, 1, None).await;
+        manager.known_peers.lock().await.check(BOB, &key(3)).unwrap();
+
+        assert!(manager.update_node_alias(ALICE, "  ".to_string()).await.is_err());
+        assert!(manager.update_node_alias(ALICE, "a\u{1b}[2J".to_string()).await.is_err());
+        manager.update_node_alias(ALICE, " friend ".to_string()).await.unwrap();
+        assert_eq!(manager.nodes.lock().await[ALICE].alias.as_deref(), Some("friend"));
+        assert!(manager.update_node_alias(BOB, "friend".to_string()).await.is_err());
+        assert!(manager.update_node_alias(ALICE, BOB.to_string()).await.is_err());
+        manager.update_node_alias(BOB, "bob".to_string()).await.unwrap();
+        assert!(manager.update_node_alias("cccc1111-0000-4000-8000-000000000004", "carol".to_string()).await.is_err());
+        assert_eq!(manager.profile.load_aliases().unwrap().get(BOB).map(String::as_str), Some("bob"));
+    }
+
+    #[tokio::test]
+    async fn clock_skew_is_reported_once_per_peer() {
+        let (manager, _dir) = manager().await;
+        let mut events = manager.subscribe();
+        manager.report_clock_skew(ALICE, unix_time() - 600).await;
+        manager.report_clock_skew(ALICE, unix_time() - 600).await;
+        let Ok(Event::PeerRejected { reason }) = events.try_recv() else { panic!("no event") };
+        assert!(reason.contains(ALICE) && reason.contains("behind"));
+        assert!(events.try_recv().is_err());
 
-        Ok(())
+        // 之后收到时间正常的公告，再次出现偏差时重新提示
+        assert!(manager.accept_presence(ALICE, Ipv4Addr::LOCALHOST, unix_time(), false).await);
+        manager.report_clock_skew(ALICE, unix_time() + 600).await;
+        let Ok(Event::PeerRejected { reason }) = events.try_recv() else { panic!("no event") };
+        assert!(reason.contains("ahead of"));
     }
 }
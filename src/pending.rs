@@ -0,0 +1,134 @@
+// pending.rs
+use serde::{Serialize, Deserialize};
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use crate::profile::{write_private, Profile};
+use crate::protocol::unix_time;
+
+const PENDING_FILE: &str = "pending.json";
+// 队列上限，避免离线节点无限积压消息
+const MAX_PENDING: usize = 1000;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PendingMessage {
+    pub id: u64, // Local number used to cancel the message
+    pub recipient: String, // UUID
+    pub text: String,
+    pub queued_at: u64, // Unix seconds
+}
+
+#[derive(Serialize, Deserialize, Default)]
+struct Stored {
+    // Never reused, so a number always names the same message
+    next_id: u64,
+    messages: Vec<PendingMessage>,
+}
+
+// Direct messages for known peers that are offline, kept until the peer comes
+// back. Only saved in the profile directory when history is enabled.
+pub struct PendingQueue {
+    path: Option<PathBuf>,
+    stored: Stored,
+}
+
+impl PendingQueue {
+    pub fn load(profile: &Profile) -> io::Result<Self> {
+        let path = profile.path(PENDING_FILE);
+        let stored = match fs::read(&path) {
+            Ok(data) => serde_json::from_slice(&data).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Stored::default(),
+            Err(e) => return Err(e),
+        };
+        Ok(PendingQueue { path: Some(path), stored })
+    }
+
+    // A queue that is lost when the node stops
+    pub fn in_memory() -> Self {
+        PendingQueue { path: None, stored: Stored::default() }
+    }
+
+    fn save(&self) -> Result<(), String> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        let data = serde_json::to_vec_pretty(&self.stored).map_err(|e| e.to_string())?;
+        write_private(path, &data)
+            .map_err(|e| format!("Failed to save pending messages: {}", e))
+    }
+
+    pub fn list(&self) -> &[PendingMessage] {
+        &self.stored.messages
+    }
+
+    pub fn push(&mut self, recipient: &str, text: &str) -> Result<u64, String> {
+        if self.stored.messages.len() >= MAX_PENDING {
+            return Err(format!("{} messages are already queued", MAX_PENDING));
+        }
+        // 编号只增不减，取消后的编号不会分给新消息
+        let id = self.stored.next_id.max(1);
+        self.stored.next_id = id + 1;
+        let queued_at = unix_time();
+        self.stored.messages.push(PendingMessage { id, recipient: recipient.to_string(), text: text.to_string(), queued_at });
+        // 保存失败时撤销，调用方会告诉用户消息没有排队，不能再悄悄投递
+        if let Err(e) = self.save() {
+            self.stored.messages.pop();
+            self.stored.next_id = id;
+            return Err(e);
+        }
+        Ok(id)
+    }
+
+    pub fn cancel(&mut self, id: u64) -> Result<PendingMessage, String> {
+        let index = self.stored.messages.iter().position(|message| message.id == id)
+            .ok_or_else(|| format!("No queued message #{}", id))?;
+        let message = self.stored.messages.remove(index);
+        if let Err(e) = self.save() {
+            self.stored.messages.insert(index, message);
+            return Err(e);
+        }
+        Ok(message)
+    }
+
+    pub fn has_for(&self, recipient: &str) -> bool {
+        self.stored.messages.iter().any(|message| message.recipient == recipient)
+    }
+
+    // The oldest message queued for `recipient`; it stays queued until delivered
+    pub fn next_for(&self, recipient: &str) -> Option<PendingMessage> {
+        self.stored.messages.iter().find(|message| message.recipient == recipient).cloned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::profile::TestDir;
+
+    #[test]
+    fn cancelled_numbers_are_not_reused() {
+        let mut queue = PendingQueue::in_memory();
+        assert_eq!(queue.push("bob", "one").unwrap(), 1);
+        assert_eq!(queue.push("bob", "two").unwrap(), 2);
+        queue.cancel(2).unwrap();
+        assert_eq!(queue.push("bob", "three").unwrap(), 3);
+        assert!(queue.cancel(2).is_err());
+        assert_eq!(queue.next_for("bob").unwrap().text, "one");
+        assert!(!queue.has_for("alice"));
+    }
+
+    #[test]
+    fn failed_saves_leave_the_queue_unchanged() {
+        let dir = TestDir::new();
+        let mut queue = PendingQueue { path: Some(dir.join("missing").join(PENDING_FILE)), stored: Stored::default() };
+        assert!(queue.push("bob", "lost").is_err());
+        assert!(queue.list().is_empty());
+        assert!(!queue.has_for("bob"));
+
+        queue.path = Some(dir.join(PENDING_FILE));
+        assert_eq!(queue.push("bob", "kept").unwrap(), 1);
+        queue.path = Some(dir.join("missing").join(PENDING_FILE));
+        assert!(queue.cancel(1).is_err());
+        assert_eq!(queue.next_for("bob").unwrap().text, "kept");
+    }
+}
@@ -0,0 +1,126 @@
+// profile.rs
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+// Local directory holding everything that must survive a restart
+#[derive(Debug, Clone)]
+pub struct Profile {
+    dir: PathBuf,
+}
+
+impl Profile {
+    pub fn open(dir: impl Into<PathBuf>) -> io::Result<Self> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir)?;
+        Ok(Profile { dir })
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    pub fn path(&self, name: &str) -> PathBuf {
+        self.dir.join(name)
+    }
+
+    // Aliases are keyed by node ID so they still apply after the peer restarts
+    pub fn load_aliases(&self) -> io::Result<HashMap<String, String>> {
+        match fs::read(self.path("aliases.json")) {
+            Ok(data) => serde_json::from_slice(&data).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(HashMap::new()),
+            Err(e) => Err(e),
+        }
+    }
+
+    pub fn save_aliases(&self, aliases: &HashMap<String, String>) -> io::Result<()> {
+        let data = serde_json::to_vec_pretty(aliases)?;
+        fs::write(self.path("aliases.json"), data)
+    }
+}
+
+// Make a file holding key material readable by its owner only
+#[cfg(unix)]
+pub fn restrict_permissions(path: &Path) -> io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    fs::set_permissions(path, fs::Permissions::from_mode(0o600))
+}
+
+#[cfg(not(unix))]
+pub fn restrict_permissions(_path: &Path) -> io::Result<()> {
+    Ok(())
+}
+
+// Create a new file that is readable by its owner only from the start, so
+// secrets are never exposed between writing and restricting them
+#[cfg(unix)]
+pub fn create_private(path: &Path) -> io::Result<fs::File> {
+    use std::os::unix::fs::OpenOptionsExt;
+    fs::OpenOptions::new().write(true).create_new(true).mode(0o600).open(path)
+}
+
+#[cfg(not(unix))]
+pub fn create_private(path: &Path) -> io::Result<fs::File> {
+    fs::OpenOptions::new().write(true).create_new(true).open(path)
+}
+
+// Make sure `path` exists and is readable by its owner only, creating it
+// that way if it is new, for files that other code writes into
+pub fn ensure_private(path: &Path) -> io::Result<()> {
+    match create_private(path) {
+        Ok(_) => Ok(()),
+        Err(e) if e.kind() == io::ErrorKind::AlreadyExists => restrict_permissions(path),
+        Err(e) => Err(e),
+    }
+}
+
+// Replace `path` with `data` through an owner-only temporary file, so the
+// contents are never readable by others, even for a moment, and a failed
+// write leaves the old file in place
+pub fn write_private(path: &Path, data: &[u8]) -> io::Result<()> {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    let tmp = PathBuf::from(tmp);
+    // 上次写入中断时可能留下临时文件
+    match fs::remove_file(&tmp) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+        _ => {},
+    }
+    let result = create_private(&tmp)
+        .and_then(|mut file| file.write_all(data).and_then(|_| file.sync_all()))
+        .and_then(|_| fs::rename(&tmp, path));
+    if result.is_err() {
+        let _ = fs::remove_file(&tmp);
+    }
+    result
+}
+
+// A fresh directory under the system temp directory for one test, deleted
+// when dropped
+#[cfg(test)]
+pub struct TestDir(PathBuf);
+
+#[cfg(test)]
+impl TestDir {
+    pub fn new() -> Self {
+        let dir = std::env::temp_dir().join(format!("p2p_chat_test_{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(&dir).expect("test directory can be created");
+        TestDir(dir)
+    }
+
+    pub fn join(&self, name: &str) -> PathBuf {
+        self.0.join(name)
+    }
+
+    pub fn profile(&self) -> Profile {
+        Profile::open(&self.0).expect("test profile can be opened")
+    }
+}
+
+#[cfg(test)]
+impl Drop for TestDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
@@ -0,0 +1,275 @@
+// protocol.rs
+use serde::{Serialize, Deserialize};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::net::Ipv4Addr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// 协议版本，收到不同版本的报文时只做降级处理
+pub const PROTOCOL_VERSION: u16 = 1;
+
+// Every datagram on the chat socket and on the multicast group is an Envelope.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Envelope {
+    pub version: u16,
+    pub sender: String, // UUID
+    pub ip: Ipv4Addr,
+    pub port: u16,
+    pub payload: Payload,
+}
+
+// 签名时间戳与本地时钟的最大偏差，超过则视为重放
+pub const MAX_CLOCK_SKEW: u64 = 120;
+
+pub fn unix_time() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+// Signed timestamps of announcements and room messages must be recent
+pub fn check_timestamp(timestamp: u64) -> Result<(), String> {
+    if unix_time().abs_diff(timestamp) > MAX_CLOCK_SKEW {
+        return Err(format!("timestamp {} is too far from the local clock", timestamp));
+    }
+    Ok(())
+}
+
+// Longest display name and status text a node may advertise, in characters
+pub const MAX_DISPLAY_NAME_LEN: usize = 32;
+pub const MAX_STATUS_LEN: usize = 80;
+// Room names are short identifiers so they fit in announcements
+pub const MAX_ROOM_NAME_LEN: usize = 32;
+// Most rooms a single announcement may list
+pub const MAX_ADVERTISED_ROOMS: usize = 32;
+
+pub fn is_valid_room_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= MAX_ROOM_NAME_LEN
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+// What a node says about its user in its announcements. Peers show it but
+// never trust it for identification; the UUID and key do that.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct PresenceInfo {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub display_name: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub status: Option<String>,
+    // Rooms the node has joined
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub rooms: Vec<String>,
+    // Passphrase-protected rooms the node has joined, with the ID of the key
+    // in use so a wrong passphrase shows up before anything is sent
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub private_rooms: BTreeMap<String, String>,
+}
+
+// Keep printable characters only and cut to `max` characters
+fn clean_text(text: Option<String>, max: usize) -> Option<String> {
+    let text: String = text?.chars().filter(|c| !c.is_control()).take(max).collect();
+    let text = text.trim();
+    (!text.is_empty()).then(|| text.to_string())
+}
+
+// A received message body without control characters, which a peer could use
+// to move the cursor or rewrite our terminal. Line breaks and tabs stay.
+pub fn clean_message(text: &str) -> String {
+    text.chars().filter(|c| !c.is_control() || *c == '\n' || *c == '\t').collect()
+}
+
+impl PresenceInfo {
+    // Strip what a peer could use to mess up our terminal
+    pub fn sanitized(self) -> Self {
+        PresenceInfo {
+            display_name: clean_text(self.display_name, MAX_DISPLAY_NAME_LEN),
+            status: clean_text(self.status, MAX_STATUS_LEN),
+            rooms: self.rooms.into_iter()
+                .filter(|room| is_valid_room_name(room))
+                .take(MAX_ADVERTISED_ROOMS)
+                .collect(),
+            private_rooms: self.private_rooms.into_iter()
+                .filter(|(room, key_id)| is_valid_room_name(room) && key_id.len() == 16 && key_id.chars().all(|c| c.is_ascii_hexdigit()))
+                .take(MAX_ADVERTISED_ROOMS)
+                .collect(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Payload {
+    // Periodic presence beacon sent to the multicast group, signed with the
+    // sender's identity key
+    Announce {
+        public_key: String,
+        timestamp: u64,
+        #[serde(flatten)]
+        info: PresenceInfo,
+        signature: String,
+    },
+    // Sent to the multicast group when a node shuts down
+    Goodbye { timestamp: u64, signature: String },
+    // A chat message encrypted with the session shared by sender and recipient
+    Chat { id: String, session: String, nonce: String, ciphertext: String },
+    // One piece of the ciphertext of a chat message too large for a single datagram
+    Fragment { id: String, index: u16, total: u16, session: String, nonce: String, ciphertext: String },
+    Ack { id: String },
+    // The recipient has no session matching the one a chat message was sealed with
+    SessionUnknown { id: String },
+    // Signed ephemeral X25519 keys used to set up an encrypted session. The
+    // initiator includes its identity key so a known peer can be verified before
+    // its first announcement arrives; the timestamp limits how long a captured
+    // handshake can be replayed.
+    Handshake { public_key: String, ephemeral: String, timestamp: u64, signature: String },
+    HandshakeReply { ephemeral: String, signature: String },
+    Ping,
+    Pong,
+    // A message for a passphrase-protected room, sent to the multicast group
+    // sealed with the room key and signed with the sender's identity key. The
+    // signed timestamp keeps captured messages from being replayed later.
+    RoomChat { id: String, room: String, key_id: String, timestamp: u64, nonce: String, ciphertext: String, signature: String },
+}
+
+// Who a chat message was addressed to
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Scope {
+    #[default]
+    Direct,
+    // Sent to every online peer
+    Broadcast,
+    // Sent to the online members of a room
+    Room { room: String },
+}
+
+// Plaintext of a chat message before it is sealed
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ChatBody {
+    pub text: String,
+    #[serde(default)]
+    pub scope: Scope,
+}
+
+// The part of an envelope that every version is expected to keep stable.
+#[derive(Deserialize, Debug, Clone)]
+pub struct Header {
+    pub version: u16,
+    pub sender: String,
+}
+
+#[derive(Debug)]
+pub enum DecodeError {
+    Malformed(serde_json::Error),
+    UnsupportedVersion(Header),
+    UnknownKind(Header),
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecodeError::Malformed(e) => write!(f, "malformed datagram: {}", e),
+            DecodeError::UnsupportedVersion(header) => write!(
+                f,
+                "unsupported protocol version {} from {} (expected {})",
+                header.version, header.sender, PROTOCOL_VERSION
+            ),
+            DecodeError::UnknownKind(header) => {
+                write!(f, "unknown payload kind from {}", header.sender)
+            }
+        }
+    }
+}
+
+// Bytes covered by the signature of an Announce or Goodbye. Everything a
+// receiver acts on is included so none of it can be swapped in transit.
+// An empty PresenceInfo adds nothing, so nameless nodes sign what they always did.
+pub fn presence_signing_bytes(kind: &str, sender: &str, ip: Ipv4Addr, port: u16, public_key: &str, timestamp: u64, info: &PresenceInfo) -> Vec<u8> {
+    let mut data = format!("{}|{}|{}|{}|{}|{}|{}", PROTOCOL_VERSION, kind, sender, ip, port, public_key, timestamp);
+    if *info != PresenceInfo::default() {
+        data.push('|');
+        data.push_str(&serde_json::to_string(info).expect("presence info serializes"));
+    }
+    data.into_bytes()
+}
+
+// Bytes covered by the signature of a Handshake (`responder_ephemeral` empty)
+// or a HandshakeReply. `timestamp` is the one the Handshake carried.
+pub fn handshake_signing_bytes(initiator: &str, responder: &str, initiator_ephemeral: &str, responder_ephemeral: &str, timestamp: u64) -> Vec<u8> {
+    let kind = if responder_ephemeral.is_empty() { "handshake" } else { "handshake_reply" };
+    format!(
+        "{}|{}|{}|{}|{}|{}|{}",
+        PROTOCOL_VERSION, kind, initiator, responder, initiator_ephemeral, responder_ephemeral, timestamp
+    ).into_bytes()
+}
+
+// Bytes covered by the signature of a RoomChat
+pub fn room_signing_bytes(sender: &str, id: &str, room: &str, key_id: &str, timestamp: u64, nonce: &str, ciphertext: &str) -> Vec<u8> {
+    format!("{}|room_chat|{}|{}|{}|{}|{}|{}|{}", PROTOCOL_VERSION, sender, id, room, key_id, timestamp, nonce, ciphertext).into_bytes()
+}
+
+impl Envelope {
+    pub fn new(sender: String, ip: Ipv4Addr, port: u16, payload: Payload) -> Self {
+        Envelope {
+            version: PROTOCOL_VERSION,
+            sender,
+            ip,
+            port,
+            payload,
+        }
+    }
+
+    pub fn encode(&self) -> Result<Vec<u8>, serde_json::Error> {
+        serde_json::to_vec(self)
+    }
+
+    // Decode a datagram. The header is parsed first so callers can still
+    // see who sent a datagram they cannot fully understand.
+    pub fn decode(data: &[u8]) -> Result<Envelope, DecodeError> {
+        let header = serde_json::from_slice::<Header>(data).map_err(DecodeError::Malformed)?;
+        if header.version != PROTOCOL_VERSION {
+            return Err(DecodeError::UnsupportedVersion(header));
+        }
+        serde_json::from_slice::<Envelope>(data).map_err(|_| DecodeError::UnknownKind(header))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn message_text_loses_control_characters_but_keeps_layout() {
+        assert_eq!(clean_message("hi\x1b[2J\x1b]0;pwned\x07 there\r"), "hi[2J]0;pwned there");
+        assert_eq!(clean_message("line one\nline two\tcol\u{9b}31m"), "line one\nline two\tcol31m");
+        assert_eq!(clean_message("你好"), "你好");
+    }
+
+    fn envelope() -> Envelope {
+        Envelope::new("alice".to_string(), Ipv4Addr::new(192, 168, 1, 2), 4000, Payload::Ack { id: "m1".to_string() })
+    }
+
+    #[test]
+    fn envelopes_round_trip() {
+        let decoded = Envelope::decode(&envelope().encode().unwrap()).unwrap();
+        assert_eq!(decoded.version, PROTOCOL_VERSION);
+        assert_eq!((decoded.sender.as_str(), decoded.ip, decoded.port), ("alice", Ipv4Addr::new(192, 168, 1, 2), 4000));
+        assert!(matches!(decoded.payload, Payload::Ack { id } if id == "m1"));
+    }
+
+    #[test]
+    fn decode_keeps_the_header_of_what_it_cannot_read() {
+        let mut value = serde_json::to_value(envelope()).unwrap();
+        value["version"] = (PROTOCOL_VERSION + 1).into();
+        match Envelope::decode(value.to_string().as_bytes()) {
+            Err(DecodeError::UnsupportedVersion(header)) => assert_eq!((header.version, header.sender.as_str()), (PROTOCOL_VERSION + 1, "alice")),
+            other => panic!("expected a version mismatch, got {:?}", other),
+        }
+
+        let mut value = serde_json::to_value(envelope()).unwrap();
+        value["payload"]["kind"] = "teleport".into();
+        assert!(matches!(Envelope::decode(value.to_string().as_bytes()), Err(DecodeError::UnknownKind(header)) if header.sender == "alice"));
+
+        assert!(matches!(Envelope::decode(b"hello"), Err(DecodeError::Malformed(_))));
+        assert!(matches!(Envelope::decode(br#"{"sender":"alice"}"#), Err(DecodeError::Malformed(_))));
+    }
+}
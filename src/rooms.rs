@@ -0,0 +1,288 @@
+// rooms.rs
+use argon2::Argon2;
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, BTreeSet, HashMap, VecDeque};
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use crate::profile::{write_private, Profile};
+use crate::protocol::{is_valid_room_name, unix_time};
+use crate::session::Session;
+
+const ROOMS_FILE: &str = "rooms.json";
+// Keys of passphrase-protected rooms, kept apart so the file can be private
+const ROOM_KEYS_FILE: &str = "room_keys.json";
+const ROOM_KDF_SALT: &str = "P2PChatBot room key v1|";
+// 每个房间在内存中保留的消息条数
+const ROOM_HISTORY_LEN: usize = 200;
+
+#[derive(Debug, Clone)]
+pub struct RoomMessage {
+    pub timestamp: u64, // Unix seconds
+    pub sender: String, // UUID
+    pub text: String,
+}
+
+// Key of a passphrase-protected room. Everyone who knows the passphrase
+// derives the same key; its ID lets peers spot a wrong passphrase without
+// trying to decrypt anything.
+#[derive(Clone)]
+pub struct RoomKey {
+    key: [u8; 32],
+    session: Session,
+}
+
+impl RoomKey {
+    // 房间名作为盐，同一口令在不同房间得到不同的密钥
+    pub fn derive(room: &str, passphrase: &str) -> Result<Self, String> {
+        if passphrase.is_empty() {
+            return Err("The passphrase must not be empty".to_string());
+        }
+        let salt = format!("{}{}", ROOM_KDF_SALT, room);
+        let mut key = [0u8; 32];
+        Argon2::default()
+            .hash_password_into(passphrase.as_bytes(), salt.as_bytes(), &mut key)
+            .map_err(|e| format!("Failed to derive room key: {}", e))?;
+        Ok(RoomKey::from_bytes(key))
+    }
+
+    // Argon2 takes a while, so derive on a blocking thread
+    pub async fn derive_in_background(room: &str, passphrase: &str) -> Result<Self, String> {
+        check_room_name(room)?;
+        let (room, passphrase) = (room.to_string(), passphrase.to_string());
+        tokio::task::spawn_blocking(move || RoomKey::derive(&room, &passphrase))
+            .await
+            .map_err(|e| format!("Failed to derive room key: {}", e))?
+    }
+
+    fn from_bytes(key: [u8; 32]) -> Self {
+        let id = hex::encode(&Sha256::digest([b"room key id|".as_slice(), &key].concat())[..8]);
+        RoomKey { key, session: Session::with_key(id, &key) }
+    }
+
+    pub fn id(&self) -> &str {
+        &self.session.id
+    }
+
+    pub fn seal(&self, aad: &str, plaintext: &[u8]) -> Result<(String, String), String> {
+        self.session.seal(aad, plaintext)
+    }
+
+    pub fn open(&self, aad: &str, nonce: &str, ciphertext: &str) -> Result<Vec<u8>, String> {
+        self.session.open(aad, nonce, ciphertext)
+    }
+}
+
+// Rooms this node has joined, persisted in the profile directory, and the
+// messages seen in them since start
+pub struct Rooms {
+    path: PathBuf,
+    keys_path: PathBuf,
+    joined: BTreeSet<String>,
+    // Passphrase-protected rooms; their traffic goes over the multicast group
+    private: BTreeMap<String, RoomKey>,
+    history: HashMap<String, VecDeque<RoomMessage>>,
+}
+
+fn invalid_data(e: impl ToString) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e.to_string())
+}
+
+// Local wall-clock time of a Unix timestamp, for display
+pub fn format_timestamp(timestamp: u64) -> String {
+    match chrono::DateTime::from_timestamp(timestamp as i64, 0) {
+        Some(time) => time.with_timezone(&chrono::Local).format("%Y-%m-%d %H:%M:%S").to_string(),
+        None => timestamp.to_string(),
+    }
+}
+
+pub fn check_room_name(name: &str) -> Result<(), String> {
+    if !is_valid_room_name(name) {
+        return Err(format!("Invalid room name {:?}: use up to 32 letters, digits, '-' or '_'", name));
+    }
+    Ok(())
+}
+
+impl Rooms {
+    pub fn load(profile: &Profile) -> io::Result<Self> {
+        let path = profile.path(ROOMS_FILE);
+        let joined = match fs::read(&path) {
+            Ok(data) => serde_json::from_slice(&data).map_err(invalid_data)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => BTreeSet::new(),
+            Err(e) => return Err(e),
+        };
+        let keys_path = profile.path(ROOM_KEYS_FILE);
+        let keys: BTreeMap<String, String> = match fs::read(&keys_path) {
+            Ok(data) => serde_json::from_slice(&data).map_err(invalid_data)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => BTreeMap::new(),
+            Err(e) => return Err(e),
+        };
+        let mut private = BTreeMap::new();
+        for (room, key) in keys {
+            let key: [u8; 32] = hex::decode(&key).map_err(invalid_data)?
+                .try_into()
+                .map_err(|_| invalid_data(format!("key of room {} has the wrong length", room)))?;
+            private.insert(room, RoomKey::from_bytes(key));
+        }
+        Ok(Rooms { path, keys_path, joined, private, history: HashMap::new() })
+    }
+
+    fn save(&self) -> Result<(), String> {
+        let data = serde_json::to_vec_pretty(&self.joined).map_err(|e| e.to_string())?;
+        fs::write(&self.path, data).map_err(|e| format!("Failed to save rooms: {}", e))?;
+
+        let keys: BTreeMap<&String, String> = self.private.iter()
+            .map(|(room, key)| (room, hex::encode(key.key)))
+            .collect();
+        let data = serde_json::to_vec_pretty(&keys).map_err(|e| e.to_string())?;
+        write_private(&self.keys_path, &data)
+            .map_err(|e| format!("Failed to save room keys: {}", e))
+    }
+
+    // Rooms joined without a passphrase
+    pub fn public_rooms(&self) -> Vec<String> {
+        self.joined.iter().cloned().collect()
+    }
+
+    // Passphrase-protected rooms and the IDs of their keys
+    pub fn private_rooms(&self) -> BTreeMap<String, String> {
+        self.private.iter().map(|(room, key)| (room.clone(), key.id().to_string())).collect()
+    }
+
+    pub fn is_member(&self, room: &str) -> bool {
+        self.joined.contains(room) || self.private.contains_key(room)
+    }
+
+    pub fn key(&self, room: &str) -> Option<&RoomKey> {
+        self.private.get(room)
+    }
+
+    // Joining a room nobody uses yet creates it. Returns false if already joined.
+    pub fn join(&mut self, room: &str) -> Result<bool, String> {
+        check_room_name(room)?;
+        if self.private.contains_key(room) {
+            return Err(format!("Already in room {} with a passphrase", room));
+        }
+        if !self.joined.insert(room.to_string()) {
+            return Ok(false);
+        }
+        self.save().map(|_| true)
+    }
+
+    pub fn join_private(&mut self, room: &str, key: RoomKey) -> Result<bool, String> {
+        check_room_name(room)?;
+        if self.joined.contains(room) {
+            return Err(format!("Already in room {} without a passphrase, leave it first", room));
+        }
+        match self.private.get(room) {
+            Some(existing) if existing.id() == key.id() => return Ok(false),
+            Some(_) => return Err(format!("Already in room {} with a different passphrase, leave it first", room)),
+            None => {},
+        }
+        self.private.insert(room.to_string(), key);
+        self.save().map(|_| true)
+    }
+
+    pub fn leave(&mut self, room: &str) -> Result<bool, String> {
+        if !self.joined.remove(room) && self.private.remove(room).is_none() {
+            return Ok(false);
+        }
+        self.history.remove(room);
+        self.save().map(|_| true)
+    }
+
+    pub fn record(&mut self, room: &str, sender: &str, text: &str) {
+        let timestamp = unix_time();
+        let messages = self.history.entry(room.to_string()).or_default();
+        if messages.len() == ROOM_HISTORY_LEN {
+            messages.pop_front();
+        }
+        messages.push_back(RoomMessage { timestamp, sender: sender.to_string(), text: text.to_string() });
+    }
+
+    // The last `count` messages of a room, oldest first
+    pub fn history(&self, room: &str, count: usize) -> Vec<RoomMessage> {
+        let Some(messages) = self.history.get(room) else {
+            return Vec::new();
+        };
+        messages.iter().skip(messages.len().saturating_sub(count)).cloned().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::profile::TestDir;
+
+    #[test]
+    fn membership_survives_a_restart() {
+        let dir = TestDir::new();
+        let mut rooms = Rooms::load(&dir.profile()).unwrap();
+        assert!(rooms.join("lobby").unwrap());
+        assert!(!rooms.join("lobby").unwrap());
+        assert!(rooms.join("not a room").is_err());
+        assert!(rooms.join("lobby!").is_err());
+        assert!(rooms.join("dev").unwrap());
+        assert!(rooms.leave("dev").unwrap());
+        assert!(!rooms.leave("dev").unwrap());
+
+        let rooms = Rooms::load(&dir.profile()).unwrap();
+        assert_eq!(rooms.public_rooms(), vec!["lobby"]);
+        assert!(rooms.is_member("lobby") && !rooms.is_member("dev"));
+    }
+
+    #[test]
+    fn history_keeps_the_latest_messages() {
+        let dir = TestDir::new();
+        let mut rooms = Rooms::load(&dir.profile()).unwrap();
+        rooms.join("lobby").unwrap();
+        for i in 0..ROOM_HISTORY_LEN + 5 {
+            rooms.record("lobby", "bob", &i.to_string());
+        }
+        let last: Vec<String> = rooms.history("lobby", 3).into_iter().map(|message| message.text).collect();
+        assert_eq!(last, vec!["202", "203", "204"]);
+        assert_eq!(rooms.history("lobby", usize::MAX).len(), ROOM_HISTORY_LEN);
+        assert!(rooms.history("dev", 3).is_empty());
+
+        rooms.leave("lobby").unwrap();
+        assert!(rooms.history("lobby", 3).is_empty());
+    }
+
+    #[test]
+    fn keys_depend_on_room_and_passphrase() {
+        let key = RoomKey::derive("vault", "open sesame").unwrap();
+        assert_eq!(key.id(), RoomKey::derive("vault", "open sesame").unwrap().id());
+        let wrong = RoomKey::derive("vault", "open sesam").unwrap();
+        assert_ne!(key.id(), wrong.id());
+        assert_ne!(key.id(), RoomKey::derive("attic", "open sesame").unwrap().id());
+        assert!(RoomKey::derive("vault", "").is_err());
+
+        let (nonce, ciphertext) = key.seal("vault|m1", b"hello").unwrap();
+        assert_eq!(key.open("vault|m1", &nonce, &ciphertext).unwrap(), b"hello");
+        assert!(wrong.open("vault|m1", &nonce, &ciphertext).is_err());
+        assert!(key.open("vault|m2", &nonce, &ciphertext).is_err());
+    }
+
+    #[test]
+    fn private_rooms_keep_their_key_across_restarts() {
+        let dir = TestDir::new();
+        let key = RoomKey::from_bytes([7; 32]);
+        let mut rooms = Rooms::load(&dir.profile()).unwrap();
+        assert!(rooms.join_private("vault", key.clone()).unwrap());
+        assert!(!rooms.join_private("vault", key.clone()).unwrap());
+        assert!(rooms.join_private("vault", RoomKey::from_bytes([8; 32])).is_err());
+        assert!(rooms.join("vault").is_err());
+        rooms.join("lobby").unwrap();
+        assert!(rooms.join_private("lobby", key.clone()).is_err());
+
+        let rooms = Rooms::load(&dir.profile()).unwrap();
+        assert_eq!(rooms.key("vault").map(RoomKey::id), Some(key.id()));
+        assert!(rooms.key("lobby").is_none());
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(dir.join(ROOM_KEYS_FILE)).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+    }
+}
@@ -0,0 +1,426 @@
+// session.rs
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, Payload as AeadPayload};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+use ed25519_dalek::VerifyingKey;
+use hkdf::Hkdf;
+use rand_core::OsRng;
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use tokio::sync::{oneshot, Mutex};
+use x25519_dalek::{PublicKey, ReusableSecret};
+use crate::identity::{self, Identity};
+use crate::protocol::{check_timestamp, handshake_signing_bytes, unix_time, Payload};
+
+const KDF_INFO: &[u8] = b"P2PChatBot session v1";
+
+// Upper bound on the encoded ciphertext of a plaintext of `plaintext_len` bytes
+pub fn sealed_len(plaintext_len: usize) -> usize {
+    (plaintext_len + 16).div_ceil(3) * 4
+}
+
+// Symmetric state shared with one peer after a successful handshake
+#[derive(Clone)]
+pub struct Session {
+    pub id: String,
+    cipher: XChaCha20Poly1305,
+    // Message ids opened under this session, shared by every clone
+    seen: Arc<std::sync::Mutex<HashSet<String>>>,
+}
+
+impl Session {
+    // Derive the session from the DH result; both sides feed in the same transcript
+    fn derive(shared: &[u8; 32], initiator: &str, responder: &str, initiator_ephemeral: &str, responder_ephemeral: &str) -> Self {
+        let transcript = format!("{}|{}|{}|{}", initiator, responder, initiator_ephemeral, responder_ephemeral);
+        let mut key = [0u8; 32];
+        Hkdf::<Sha256>::new(Some(transcript.as_bytes()), shared)
+            .expand(KDF_INFO, &mut key)
+            .expect("32 bytes is a valid HKDF output length");
+        let id = hex::encode(&Sha256::digest(transcript.as_bytes())[..8]);
+        Session { id, cipher: XChaCha20Poly1305::new(&key.into()), seen: Arc::default() }
+    }
+
+    // A session over a key agreed some other way, e.g. derived from a room passphrase
+    pub fn with_key(id: String, key: &[u8; 32]) -> Self {
+        Session { id, cipher: XChaCha20Poly1305::new(key.into()), seen: Arc::default() }
+    }
+
+    // Returns (nonce, ciphertext), both text-encoded for the wire
+    pub fn seal(&self, aad: &str, plaintext: &[u8]) -> Result<(String, String), String> {
+        let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let ciphertext = self.cipher
+            .encrypt(&nonce, AeadPayload { msg: plaintext, aad: aad.as_bytes() })
+            .map_err(|_| "encryption failed".to_string())?;
+        Ok((hex::encode(nonce), BASE64.encode(ciphertext)))
+    }
+
+    // Returns true the first time a message id is opened. Ids are kept for as
+    // long as the session lives, so a captured datagram cannot be shown twice.
+    pub fn first_use(&self, id: &str) -> bool {
+        self.seen.lock().unwrap().insert(id.to_string())
+    }
+
+    pub fn open(&self, aad: &str, nonce: &str, ciphertext: &str) -> Result<Vec<u8>, String> {
+        let nonce = hex::decode(nonce).map_err(|e| format!("invalid nonce: {}", e))?;
+        if nonce.len() != 24 {
+            return Err("invalid nonce length".to_string());
+        }
+        let ciphertext = BASE64.decode(ciphertext).map_err(|e| format!("invalid ciphertext: {}", e))?;
+        self.cipher
+            .decrypt(XNonce::from_slice(&nonce), AeadPayload { msg: &ciphertext, aad: aad.as_bytes() })
+            .map_err(|_| "message could not be authenticated".to_string())
+    }
+}
+
+struct Established {
+    session: Session,
+    // Set when the peer initiated; lets us repeat our reply to a retransmitted Handshake
+    accepted: Option<(String, Payload)>,
+}
+
+struct Pending {
+    secret: ReusableSecret,
+    ephemeral: String,
+    timestamp: u64,
+    waiters: Vec<oneshot::Sender<Session>>,
+}
+
+#[derive(Default)]
+struct Inner {
+    established: HashMap<String, Established>,
+    // Sessions from handshakes a peer sent us that it has not used yet. A
+    // replayed handshake ends up here and never replaces the live session.
+    proposed: HashMap<String, Established>,
+    pending: HashMap<String, Pending>,
+}
+
+// Encrypted sessions with peers, keyed by peer UUID
+#[derive(Clone)]
+pub struct Sessions {
+    identity: Arc<Identity>,
+    inner: Arc<Mutex<Inner>>,
+}
+
+fn decode_ephemeral(ephemeral: &str) -> Result<PublicKey, String> {
+    let bytes: [u8; 32] = hex::decode(ephemeral)
+        .map_err(|e| format!("invalid ephemeral key: {}", e))?
+        .try_into()
+        .map_err(|_| "ephemeral key has the wrong length".to_string())?;
+    Ok(PublicKey::from(bytes))
+}
+
+impl Sessions {
+    pub fn new(identity: Arc<Identity>) -> Self {
+        Sessions { identity, inner: Arc::new(Mutex::new(Inner::default())) }
+    }
+
+    // The session to send with: the established one, else one the peer proposed
+    pub async fn get(&self, peer: &str) -> Option<Session> {
+        let inner = self.inner.lock().await;
+        inner.established.get(peer).or_else(|| inner.proposed.get(peer)).map(|e| e.session.clone())
+    }
+
+    // The session with `peer` that has id `id`, to open a message sealed with it
+    pub async fn find(&self, peer: &str, id: &str) -> Option<Session> {
+        let inner = self.inner.lock().await;
+        let session = [inner.established.get(peer), inner.proposed.get(peer)].into_iter()
+            .flatten()
+            .find(|e| e.session.id == id)
+            .map(|e| e.session.clone());
+        session
+    }
+
+    // A message sealed with session `id` was authenticated, so the peer really
+    // holds it; a proposed session with that id replaces the established one
+    pub async fn confirm(&self, peer: &str, id: &str) {
+        let mut inner = self.inner.lock().await;
+        if inner.proposed.get(peer).is_some_and(|e| e.session.id == id) {
+            let confirmed = inner.proposed.remove(peer).expect("proposed session present");
+            inner.established.insert(peer.to_string(), confirmed);
+        }
+    }
+
+    // Drop everything we share with a peer, e.g. when it goes offline
+    pub async fn forget(&self, peer: &str) {
+        let mut inner = self.inner.lock().await;
+        inner.established.remove(peer);
+        inner.proposed.remove(peer);
+        inner.pending.remove(peer);
+    }
+
+    // Drop sessions with every peer `keep` returns false for
+    pub async fn retain(&self, keep: impl Fn(&str) -> bool) {
+        let mut inner = self.inner.lock().await;
+        inner.established.retain(|peer, _| keep(peer));
+        inner.proposed.retain(|peer, _| keep(peer));
+        inner.pending.retain(|peer, _| keep(peer));
+    }
+
+    // Start (or join) a handshake with `peer`. Returns the Handshake payload to
+    // send, which stays the same for retransmissions, and a receiver for the result.
+    pub async fn begin(&self, peer: &str) -> (Payload, oneshot::Receiver<Session>) {
+        let (tx, rx) = oneshot::channel();
+        let mut inner = self.inner.lock().await;
+        let pending = inner.pending.entry(peer.to_string()).or_insert_with(|| {
+            let secret = ReusableSecret::random_from_rng(OsRng);
+            let ephemeral = hex::encode(PublicKey::from(&secret).as_bytes());
+            Pending { secret, ephemeral, timestamp: unix_time(), waiters: Vec::new() }
+        });
+        pending.waiters.push(tx);
+        let data = handshake_signing_bytes(&self.identity.node_id(), peer, &pending.ephemeral, "", pending.timestamp);
+        let handshake = Payload::Handshake {
+            public_key: self.identity.public_key_hex(),
+            ephemeral: pending.ephemeral.clone(),
+            timestamp: pending.timestamp,
+            signature: self.identity.sign_hex(&data),
+        };
+        (handshake, rx)
+    }
+
+    // Whether a Handshake was really signed by `peer`, whatever its timestamp
+    pub fn signed_by(&self, peer: &str, peer_key: &VerifyingKey, ephemeral: &str, timestamp: u64, signature: &str) -> bool {
+        let data = handshake_signing_bytes(peer, &self.identity.node_id(), ephemeral, "", timestamp);
+        identity::verify(peer_key, &data, signature).is_ok()
+    }
+
+    // Handle a Handshake from `peer`. Returns the reply to send, or None when our
+    // own concurrent handshake takes precedence. The new session is only
+    // proposed: the current one stays in use until the peer sends with the new one.
+    pub async fn accept(&self, peer: &str, peer_key: &VerifyingKey, ephemeral: &str, timestamp: u64, signature: &str) -> Result<Option<Payload>, String> {
+        let own_id = self.identity.node_id();
+        identity::verify(peer_key, &handshake_signing_bytes(peer, &own_id, ephemeral, "", timestamp), signature)?;
+        check_timestamp(timestamp)?;
+        let their_public = decode_ephemeral(ephemeral)?;
+
+        let mut inner = self.inner.lock().await;
+        for answered in [inner.established.get(peer), inner.proposed.get(peer)].into_iter().flatten() {
+            if let Some((seen, reply)) = &answered.accepted {
+                if seen == ephemeral {
+                    return Ok(Some(reply.clone()));
+                }
+            }
+        }
+        // 双方同时发起握手时，UUID 较小的一方作为发起方
+        if inner.pending.contains_key(peer) && own_id.as_str() < peer {
+            return Ok(None);
+        }
+
+        let secret = ReusableSecret::random_from_rng(OsRng);
+        let our_ephemeral = hex::encode(PublicKey::from(&secret).as_bytes());
+        let shared = secret.diffie_hellman(&their_public);
+        let session = Session::derive(shared.as_bytes(), peer, &own_id, ephemeral, &our_ephemeral);
+        let signature = self.identity.sign_hex(&handshake_signing_bytes(peer, &own_id, ephemeral, &our_ephemeral, timestamp));
+        let reply = Payload::HandshakeReply { ephemeral: our_ephemeral, signature };
+        let accepted = Established { session: session.clone(), accepted: Some((ephemeral.to_string(), reply.clone())) };
+
+        match inner.pending.remove(peer) {
+            // 我们也在等待会话：对方的握手优先，直接使用
+            Some(pending) => {
+                for waiter in pending.waiters {
+                    let _ = waiter.send(session.clone());
+                }
+                inner.established.insert(peer.to_string(), accepted);
+            },
+            None => {
+                inner.proposed.insert(peer.to_string(), accepted);
+            },
+        }
+        Ok(Some(reply))
+    }
+
+    // Handle the HandshakeReply to a handshake we started
+    pub async fn complete(&self, peer: &str, peer_key: &VerifyingKey, ephemeral: &str, signature: &str) -> Result<(), String> {
+        let own_id = self.identity.node_id();
+        let mut inner = self.inner.lock().await;
+        let Some(pending) = inner.pending.remove(peer) else {
+            // Duplicate reply to a handshake that already completed
+            return Ok(());
+        };
+        let data = handshake_signing_bytes(&own_id, peer, &pending.ephemeral, ephemeral, pending.timestamp);
+        let result = identity::verify(peer_key, &data, signature).and_then(|_| decode_ephemeral(ephemeral));
+        let their_public = match result {
+            Ok(public) => public,
+            Err(e) => {
+                // 验证失败时保留等待中的握手，等待真正的回复
+                inner.pending.insert(peer.to_string(), pending);
+                return Err(e);
+            }
+        };
+
+        let shared = pending.secret.diffie_hellman(&their_public);
+        let session = Session::derive(shared.as_bytes(), &own_id, peer, &pending.ephemeral, ephemeral);
+        for waiter in pending.waiters {
+            let _ = waiter.send(session.clone());
+        }
+        inner.proposed.remove(peer);
+        inner.established.insert(peer.to_string(), Established { session, accepted: None });
+        Ok(())
+    }
+
+    // Give up on a handshake that never got a reply
+    pub async fn abandon(&self, peer: &str) {
+        self.inner.lock().await.pending.remove(peer);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::profile::TestDir;
+
+    fn identity() -> Arc<Identity> {
+        Arc::new(Identity::load_or_create(&TestDir::new().profile()).unwrap())
+    }
+
+    // Let `to` handle a Handshake payload that `from` produced
+    async fn accept(to: &Sessions, from: &Sessions, handshake: &Payload) -> Result<Option<Payload>, String> {
+        let Payload::Handshake { ephemeral, timestamp, signature, .. } = handshake else { panic!("not a handshake") };
+        to.accept(&from.identity.node_id(), &from.identity.public_key(), ephemeral, *timestamp, signature).await
+    }
+
+    // Run a full handshake from `a` to `b`, returning both ends of the session
+    async fn handshake(a: &Sessions, b: &Sessions) -> (Session, Session) {
+        let b_id = b.identity.node_id();
+        let (handshake, rx) = a.begin(&b_id).await;
+        let reply = accept(b, a, &handshake).await.unwrap();
+        let Some(Payload::HandshakeReply { ephemeral, signature }) = reply else { panic!("no reply") };
+        a.complete(&b_id, &b.identity.public_key(), &ephemeral, &signature).await.unwrap();
+        let on_a = rx.await.unwrap();
+        let on_b = b.find(&a.identity.node_id(), &on_a.id).await.unwrap();
+        (on_a, on_b)
+    }
+
+    #[test]
+    fn seal_and_open() {
+        let session = Session::with_key("s".into(), &[7; 32]);
+        let (nonce, ciphertext) = session.seal("aad", "héllo".as_bytes()).unwrap();
+        assert!(ciphertext.len() <= sealed_len("héllo".len()));
+        assert_eq!(session.open("aad", &nonce, &ciphertext).unwrap(), "héllo".as_bytes());
+
+        // 附加数据、密文或密钥不对时都无法通过认证
+        assert!(session.open("other", &nonce, &ciphertext).is_err());
+        let mut tampered = BASE64.decode(&ciphertext).unwrap();
+        tampered[0] ^= 1;
+        assert!(session.open("aad", &nonce, &BASE64.encode(tampered)).is_err());
+        assert!(Session::with_key("s".into(), &[8; 32]).open("aad", &nonce, &ciphertext).is_err());
+        assert!(session.open("aad", "abcd", &ciphertext).is_err());
+        assert!(session.open("aad", "not hex", &ciphertext).is_err());
+    }
+
+    #[test]
+    fn message_ids_are_used_once_across_clones() {
+        let session = Session::with_key("s".into(), &[7; 32]);
+        assert!(session.first_use("m1"));
+        assert!(!session.clone().first_use("m1"));
+        assert!(session.first_use("m2"));
+    }
+
+    #[tokio::test]
+    async fn handshake_agrees_on_a_session() {
+        let (a, b) = (Sessions::new(identity()), Sessions::new(identity()));
+        let (on_a, on_b) = handshake(&a, &b).await;
+        assert_eq!(on_a.id, on_b.id);
+        assert_eq!(a.get(&b.identity.node_id()).await.unwrap().id, on_a.id);
+
+        let (nonce, ciphertext) = on_a.seal("aad", b"from a").unwrap();
+        assert_eq!(on_b.open("aad", &nonce, &ciphertext).unwrap(), b"from a");
+        let (nonce, ciphertext) = on_b.seal("aad", b"from b").unwrap();
+        assert_eq!(on_a.open("aad", &nonce, &ciphertext).unwrap(), b"from b");
+
+        // 新的握手得到新的会话
+        a.forget(&b.identity.node_id()).await;
+        let (again, _) = handshake(&a, &b).await;
+        assert_ne!(again.id, on_a.id);
+    }
+
+    #[tokio::test]
+    async fn retransmitted_handshake_gets_the_same_reply() {
+        let (a, b) = (Sessions::new(identity()), Sessions::new(identity()));
+        let (handshake, _rx) = a.begin(&b.identity.node_id()).await;
+        let first = accept(&b, &a, &handshake).await.unwrap().unwrap();
+        let second = accept(&b, &a, &handshake).await.unwrap().unwrap();
+        let (Payload::HandshakeReply { ephemeral: first, .. }, Payload::HandshakeReply { ephemeral: second, .. }) = (first, second) else {
+            panic!("not a reply")
+        };
+        assert_eq!(first, second);
+    }
+
+    #[tokio::test]
+    async fn replayed_handshake_does_not_replace_the_live_session() {
+        let (a, b) = (Sessions::new(identity()), Sessions::new(identity()));
+        let (a_id, b_id) = (a.identity.node_id(), b.identity.node_id());
+        let (captured, rx) = a.begin(&b_id).await;
+        let Some(Payload::HandshakeReply { ephemeral, signature }) = accept(&b, &a, &captured).await.unwrap() else { panic!("no reply") };
+        a.complete(&b_id, &b.identity.public_key(), &ephemeral, &signature).await.unwrap();
+        let first = rx.await.unwrap();
+        b.confirm(&a_id, &first.id).await;
+
+        // a 重新握手：在 a 用新会话发消息之前，b 继续使用旧会话
+        a.forget(&b_id).await;
+        let (renewed, _rx) = a.begin(&b_id).await;
+        accept(&b, &a, &renewed).await.unwrap();
+        let second = a.get(&b_id).await;
+        assert!(second.is_none(), "a has not seen the reply yet");
+        assert_eq!(b.get(&a_id).await.unwrap().id, first.id);
+
+        // 重放最初的握手同样只会得到一个待确认的会话
+        a.abandon(&b_id).await;
+        accept(&b, &a, &captured).await.unwrap();
+        assert_eq!(b.get(&a_id).await.unwrap().id, first.id);
+        assert!(b.find(&a_id, &first.id).await.is_some());
+    }
+
+    #[tokio::test]
+    async fn confirmed_session_replaces_the_old_one() {
+        let (a, b) = (Sessions::new(identity()), Sessions::new(identity()));
+        let (a_id, b_id) = (a.identity.node_id(), b.identity.node_id());
+        let (first, _) = handshake(&a, &b).await;
+        b.confirm(&a_id, &first.id).await;
+        a.forget(&b_id).await;
+        let (second, _) = handshake(&a, &b).await;
+        assert_eq!(b.get(&a_id).await.unwrap().id, first.id);
+        b.confirm(&a_id, &second.id).await;
+        assert_eq!(b.get(&a_id).await.unwrap().id, second.id);
+        assert!(b.find(&a_id, &first.id).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn rejects_stale_handshakes() {
+        let (a, b) = (Sessions::new(identity()), Sessions::new(identity()));
+        let (a_id, b_id) = (a.identity.node_id(), b.identity.node_id());
+        let ephemeral = hex::encode(PublicKey::from(&ReusableSecret::random_from_rng(OsRng)).as_bytes());
+        let timestamp = unix_time() - 3600;
+        let signature = a.identity.sign_hex(&handshake_signing_bytes(&a_id, &b_id, &ephemeral, "", timestamp));
+        let result = b.accept(&a_id, &a.identity.public_key(), &ephemeral, timestamp, &signature).await;
+        assert!(result.is_err());
+        // 改动时间戳会使签名失效
+        let result = b.accept(&a_id, &a.identity.public_key(), &ephemeral, unix_time(), &signature).await;
+        assert!(result.is_err());
+        assert!(b.get(&a_id).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn rejects_handshakes_signed_by_someone_else() {
+        let (a, b, mallory) = (Sessions::new(identity()), Sessions::new(identity()), Sessions::new(identity()));
+        let (a_id, b_id) = (a.identity.node_id(), b.identity.node_id());
+
+        // mallory 用自己的密钥冒充 a 发起握手
+        let (handshake, _rx) = mallory.begin(&b_id).await;
+        let Payload::Handshake { ephemeral, timestamp, signature, .. } = handshake else { panic!("not a handshake") };
+        assert!(b.accept(&a_id, &a.identity.public_key(), &ephemeral, timestamp, &signature).await.is_err());
+        assert!(b.get(&a_id).await.is_none());
+
+        // 伪造的回复不会完成握手，真正的回复仍然可以
+        let (handshake, rx) = a.begin(&b_id).await;
+        let (forged, _) = mallory.begin(&a_id).await;
+        let Payload::Handshake { ephemeral: forged_ephemeral, signature: forged_signature, .. } = forged else { panic!("not a handshake") };
+        assert!(a.complete(&b_id, &b.identity.public_key(), &forged_ephemeral, &forged_signature).await.is_err());
+
+        let reply = accept(&b, &a, &handshake).await.unwrap();
+        let Some(Payload::HandshakeReply { ephemeral, signature }) = reply else { panic!("no reply") };
+        a.complete(&b_id, &b.identity.public_key(), &ephemeral, &signature).await.unwrap();
+        assert_eq!(rx.await.unwrap().id, b.get(&a_id).await.unwrap().id);
+    }
+}
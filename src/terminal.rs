@@ -1,60 +1,226 @@
-use tokio::io::{self, AsyncBufReadExt, BufReader};
+use tokio::io;
+use tokio::sync::mpsc;
+use tokio::time::{self, Duration};
+use crate::chat_node::{ChatNode, EventStream};
+use crate::command_registry;
 use crate::commands::CommandHandler;
+use crate::console::{self, output};
+use crate::profile::ensure_private;
+use rustyline::completion::Completer;
+use rustyline::error::ReadlineError;
+use rustyline::highlight::Highlighter;
+use rustyline::hint::Hinter;
+use rustyline::validate::Validator;
+use rustyline::history::DefaultHistory;
+use rustyline::{Config, Context, Editor, ExternalPrinter, Helper};
 use std::future::Future;
+use std::path::{Path, PathBuf};
 use std::pin::Pin;
 use std::sync::Arc;
 
-pub async fn run_terminal(command_handler: Arc<CommandHandler>) -> io::Result<()> {
-    let stdin = io::stdin();  // 获取异步的标准输入流
-    let reader = BufReader::new(stdin);
-    let mut lines = reader.lines();
-    println!("run_terminal started");
-    while let Some(line) = lines.next_line().await? {
-        if line.trim().eq_ignore_ascii_case("exit") {
-            std::process::exit(0);
+const PROMPT: &str = "> ";
+const COMMAND_HISTORY_FILE: &str = "command_history.txt";
+const COMMAND_HISTORY_LEN: usize = 1000;
+// How often the names offered by tab completion are refreshed
+const NAMES_REFRESH_INTERVAL: Duration = Duration::from_secs(1);
+
+// Completes command names, and peer and room names where a command expects them
+struct PromptHelper {
+    names: Arc<std::sync::Mutex<Names>>,
+}
+
+// Refreshed from NodeManager in the background; completion must not wait for it
+#[derive(Default)]
+struct Names {
+    // Aliases, display names and UUIDs
+    peers: Vec<String>,
+    rooms: Vec<String>,
+}
+
+impl Completer for PromptHelper {
+    type Candidate = String;
+
+    fn complete(&self, line: &str, pos: usize, _ctx: &Context<'_>) -> rustyline::Result<(usize, Vec<String>)> {
+        let start = line[..pos].rfind(char::is_whitespace).map_or(0, |i| i + 1);
+        let previous: Vec<&str> = line[..start].split_whitespace().collect();
+        let names = self.names.lock().unwrap();
+        Ok((start, command_registry::complete(&previous, &line[start..pos], &names.peers, &names.rooms)))
+    }
+}
+
+impl Hinter for PromptHelper {
+    type Hint = String;
+}
+
+impl Highlighter for PromptHelper {}
+
+impl Validator for PromptHelper {}
+
+impl Helper for PromptHelper {}
+
+// Passphrases must not end up in the history file
+fn is_secret(line: &str) -> bool {
+    let args: Vec<&str> = line.split_whitespace().collect();
+    match args.as_slice() {
+        [name, _, _, ..] => command_registry::find(name).is_some_and(|command| command.name == "join"),
+        _ => false,
+    }
+}
+
+// The line with any passphrase hidden, for echoing a command back
+pub(crate) fn masked(line: &str) -> String {
+    if !is_secret(line) {
+        return line.to_string();
+    }
+    let args: Vec<&str> = line.split_whitespace().take(2).collect();
+    format!("{} ********", args.join(" "))
+}
+
+// Line-based prompt on stdin, the default frontend
+pub async fn run_terminal(node: &ChatNode) -> io::Result<()> {
+    let command_handler = Arc::new(CommandHandler::new(node.node_manager(), node.interfaces().to_vec()));
+    let node_manager = node.node_manager();
+    let names = Arc::new(std::sync::Mutex::new(Names::default()));
+    let latest = Arc::clone(&names);
+    let refresher = tokio::spawn(async move {
+        let mut interval = time::interval(NAMES_REFRESH_INTERVAL);
+        loop {
+            interval.tick().await;
+            let manager = node_manager.lock().await;
+            let names = Names { peers: manager.peer_names().await, rooms: manager.joined_rooms().await };
+            *latest.lock().unwrap() = names;
+        }
+    });
+
+    let config = Config::builder().max_history_size(COMMAND_HISTORY_LEN).map_err(io::Error::other)?.build();
+    let mut editor: Editor<PromptHelper, DefaultHistory> = Editor::with_config(config).map_err(io::Error::other)?;
+    editor.set_helper(Some(PromptHelper { names }));
+    // 命令历史总是保存；未开启 --history 时只保存命令和收件人，不保存消息正文
+    let history_path = node.profile().path(COMMAND_HISTORY_FILE);
+    let keep_bodies = node.config().history;
+    if history_path.exists() {
+        if let Err(e) = editor.load_history(&history_path) {
+            log::warn!("Failed to load {}: {}", history_path.display(), e);
         }
-        let command_future = process_command(&line,  command_handler.clone()).await;
+    }
+    // 输出经由 rustyline 打印，避免打断正在输入的行；标准输入不是终端时照常打印
+    match editor.create_external_printer() {
+        Ok(mut printer) => {
+            let (output_tx, mut output_rx) = mpsc::unbounded_channel();
+            console::redirect(output_tx);
+            std::thread::spawn(move || {
+                while let Some(line) = output_rx.blocking_recv() {
+                    if printer.print(line).is_err() {
+                        break;
+                    }
+                }
+            });
+        },
+        Err(e) => log::info!("Printing directly, no line editing: {}", e),
+    }
+
+    // readline 会阻塞，放到单独的线程里读取输入
+    let (line_tx, mut line_rx) = mpsc::channel(1);
+    let reader = tokio::task::spawn_blocking(move || read_lines(editor, history_path, keep_bodies, line_tx));
+    output!("run_terminal started");
+    while let Some(line) = line_rx.recv().await {
+        let command_future = process_command(&line, command_handler.clone()).await;
         command_future.await;
     }
+    refresher.abort();
+    reader.await.map_err(io::Error::other)?
+}
 
-    Ok(())
+// Print every event as one line, for the terminal and headless mode
+pub async fn print_events(mut events: EventStream) {
+    while let Some(event) = events.next().await {
+        output!("{}", event);
+    }
+}
+
+// Read lines until exit, Ctrl-C or end of input, passing each one on and
+// saving them to `history_path`
+fn read_lines(mut editor: Editor<PromptHelper, DefaultHistory>, history_path: PathBuf, keep_bodies: bool, line_tx: mpsc::Sender<String>) -> io::Result<()> {
+    loop {
+        let line = match editor.readline(PROMPT) {
+            Ok(line) => line,
+            Err(ReadlineError::Interrupted) | Err(ReadlineError::Eof) => return Ok(()),
+            Err(e) => return Err(io::Error::other(e)),
+        };
+        let trimmed = line.trim();
+        if command_registry::is_exit(trimmed) {
+            return Ok(());
+        }
+        if let Some(entry) = history_entry(trimmed, keep_bodies) {
+            let _ = editor.add_history_entry(entry);
+            save_history(&mut editor, &history_path);
+        }
+        if line_tx.blocking_send(line).is_err() {
+            return Ok(());
+        }
+    }
+}
+
+// What of `line` goes into the command history. Message bodies are only kept
+// when message history is on, like the messages themselves.
+fn history_entry(line: &str, keep_bodies: bool) -> Option<String> {
+    if line.is_empty() || is_secret(line) {
+        return None;
+    }
+    if keep_bodies {
+        return Some(line.to_string());
+    }
+    command_registry::without_message(line)
 }
 
-async fn process_command(input: &str, command_handler: Arc<CommandHandler>) -> Pin<Box<dyn Future<Output = ()> + Send>> {
+// The file may hold message bodies, so it is created owner-only before
+// anything is appended to it
+fn save_history(editor: &mut Editor<PromptHelper, DefaultHistory>, path: &Path) {
+    if let Err(e) = ensure_private(path).and_then(|_| editor.append_history(path).map_err(io::Error::other)) {
+        log::warn!("Failed to save {}: {}", path.display(), e);
+    }
+}
+
+pub(crate) async fn process_command(input: &str, command_handler: Arc<CommandHandler>) -> Pin<Box<dyn Future<Output = ()> + Send>> {
     let trimmed_input = input.trim();
     if trimmed_input.is_empty() {
         // 如果输入为空或者只有空白字符，则不执行任何操作
         return Box::pin(async {});
     }
 
-    let args: Vec<&str> = trimmed_input.split_whitespace().collect();
-    match args.first() {
-        Some(&"list_users") => {
-            let handler = Arc::clone(&command_handler);
-            Box::pin(async move {
-                handler.list_users().await;
-            })
-        },
-        Some(&"send_message") if args.len() > 2 => {
-            let handler = Arc::clone(&command_handler);
-            let identifier = args[1].to_string();
-            let message = args[2].to_string();
-            Box::pin(async move {
-                handler.send_message(&identifier, &message).await;
-            })
-        },
-        Some(&"update_alias") if args.len() > 2 => {
-            let handler = Arc::clone(&command_handler);
-            let uuid = args[1].to_string();
-            let alias = args[2].to_string();
-            Box::pin(async move {
-                handler.update_alias(&uuid, &alias).await;
-            })
-        },
-        _ => Box::pin(async {
-            println!("Invalid command or insufficient arguments.");
+    match command_registry::dispatch(trimmed_input, command_handler) {
+        Ok(command_future) => command_future,
+        Err(e) => Box::pin(async move {
+            output!("{}", e);
         }),
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::profile::TestDir;
 
+    fn save_lines(path: &Path, keep_bodies: bool, lines: &[&str]) {
+        let mut editor: Editor<PromptHelper, DefaultHistory> = Editor::new().unwrap();
+        for line in lines {
+            if let Some(entry) = history_entry(line, keep_bodies) {
+                editor.add_history_entry(entry).unwrap();
+                save_history(&mut editor, path);
+            }
+        }
+    }
+
+    #[test]
+    fn message_bodies_stay_off_disk_without_history() {
+        let dir = TestDir::new();
+        let path = dir.join(COMMAND_HISTORY_FILE);
+        save_lines(&path, false, &["msg 'Bob Smith' secret plan", "shout secret plan", "say lobby secret plan", "join vault hunter2", "list_users"]);
+        let saved = std::fs::read_to_string(&path).unwrap();
+        assert!(!saved.contains("secret plan") && !saved.contains("hunter2"));
+        assert!(saved.contains("msg 'Bob Smith'") && saved.contains("say lobby") && saved.contains("list_users"));
+
+        save_lines(&path, true, &["msg bob kept plan"]);
+        assert!(std::fs::read_to_string(&path).unwrap().contains("msg bob kept plan"));
+    }
+}
@@ -0,0 +1,484 @@
+// tui.rs
+use chrono::Local;
+use crossterm::event::{self, Event as InputEvent, KeyCode, KeyEvent, KeyEventKind, KeyModifiers};
+use ratatui::layout::{Constraint, Layout, Position, Rect};
+use ratatui::style::{Modifier, Style};
+use ratatui::text::Line;
+use ratatui::widgets::{Block, List, ListItem, ListState, Paragraph};
+use ratatui::{DefaultTerminal, Frame};
+use std::collections::{BTreeSet, HashMap, VecDeque};
+use std::io;
+use std::sync::Arc;
+use tokio::sync::{mpsc, Mutex};
+use tokio::time::{self, Duration};
+use unicode_width::UnicodeWidthChar;
+use crate::command_registry;
+use crate::chat_node::{ChatNode, EventStream};
+use crate::commands::CommandHandler;
+use crate::console;
+use crate::events::{Conversation, Event};
+use crate::node_manager::NodeManager;
+use crate::terminal;
+
+const SIDEBAR_WIDTH: u16 = 30;
+// 每个窗格保留的行数
+const PANE_HISTORY: usize = 2000;
+const SCROLL_STEP: usize = 10;
+// How often the peer sidebar is refreshed from NodeManager
+const REFRESH_INTERVAL: Duration = Duration::from_millis(500);
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum PaneKey {
+    // Command output and notifications
+    System,
+    Broadcast,
+    Room(String),
+    Peer(String), // UUID
+}
+
+impl From<Conversation> for PaneKey {
+    fn from(conversation: Conversation) -> Self {
+        match conversation {
+            Conversation::Peer(uuid) => PaneKey::Peer(uuid),
+            Conversation::Broadcast => PaneKey::Broadcast,
+            Conversation::Room(room) => PaneKey::Room(room),
+        }
+    }
+}
+
+#[derive(Default)]
+struct Pane {
+    lines: VecDeque<String>,
+    unread: usize,
+    // Lines scrolled up from the bottom
+    scroll: usize,
+}
+
+struct App {
+    uuid: String,
+    panes: HashMap<PaneKey, Pane>,
+    selected: PaneKey,
+    // Online peers as (UUID, name), refreshed from NodeManager
+    peers: Vec<(String, String)>,
+    rooms: Vec<String>,
+    input: String,
+    // Cursor position in characters
+    cursor: usize,
+    quit: bool,
+}
+
+fn timestamp() -> String {
+    Local::now().format("%H:%M:%S").to_string()
+}
+
+fn short_uuid(uuid: &str) -> &str {
+    uuid.get(..8).unwrap_or(uuid)
+}
+
+// Terminal columns a character takes; CJK characters take two
+fn char_width(c: char) -> usize {
+    c.width().unwrap_or(0)
+}
+
+// Split `line` into pieces at most `width` columns wide
+fn wrap(line: &str, width: usize) -> Vec<String> {
+    if line.is_empty() || width == 0 {
+        return vec![String::new()];
+    }
+    let mut pieces = Vec::new();
+    let mut current = String::new();
+    let mut used = 0;
+    for c in line.chars() {
+        let w = char_width(c);
+        if used + w > width && !current.is_empty() {
+            pieces.push(std::mem::take(&mut current));
+            used = 0;
+        }
+        current.push(c);
+        used += w;
+    }
+    pieces.push(current);
+    pieces
+}
+
+// The part of `input` that fits in `width` columns with the cursor (a
+// character index) in view, and the column the cursor is at
+fn input_window(input: &str, cursor: usize, width: usize) -> (String, usize) {
+    let chars: Vec<char> = input.chars().collect();
+    let mut start = 0;
+    let mut column: usize = chars[..cursor].iter().map(|&c| char_width(c)).sum();
+    // 输入过长时从左侧整字符截掉，直到光标落在框内
+    while column >= width && start < cursor {
+        column -= char_width(chars[start]);
+        start += 1;
+    }
+    let mut visible = String::new();
+    let mut used = 0;
+    for &c in &chars[start..] {
+        if used + char_width(c) > width {
+            break;
+        }
+        visible.push(c);
+        used += char_width(c);
+    }
+    (visible, column)
+}
+
+impl App {
+    fn new(uuid: String) -> Self {
+        App {
+            uuid,
+            panes: HashMap::new(),
+            selected: PaneKey::System,
+            peers: Vec::new(),
+            rooms: Vec::new(),
+            input: String::new(),
+            cursor: 0,
+            quit: false,
+        }
+    }
+
+    fn name_of(&self, uuid: &str) -> String {
+        if uuid == self.uuid {
+            return "me".to_string();
+        }
+        self.peers.iter()
+            .find(|(id, _)| id == uuid)
+            .map(|(_, name)| name.clone())
+            .unwrap_or_else(|| short_uuid(uuid).to_string())
+    }
+
+    // Sidebar entries in display order: fixed panes, rooms, then peers
+    fn entries(&self) -> Vec<PaneKey> {
+        let mut entries = vec![PaneKey::System, PaneKey::Broadcast];
+        let rooms: BTreeSet<&String> = self.rooms.iter()
+            .chain(self.panes.keys().filter_map(|key| match key {
+                PaneKey::Room(room) => Some(room),
+                _ => None,
+            }))
+            .collect();
+        entries.extend(rooms.into_iter().map(|room| PaneKey::Room(room.clone())));
+        entries.extend(self.peers.iter().map(|(uuid, _)| PaneKey::Peer(uuid.clone())));
+        // 已下线但有聊天记录的节点排在最后
+        let mut offline: Vec<&String> = self.panes.keys()
+            .filter_map(|key| match key {
+                PaneKey::Peer(uuid) if !self.peers.iter().any(|(id, _)| id == uuid) => Some(uuid),
+                _ => None,
+            })
+            .collect();
+        offline.sort();
+        entries.extend(offline.into_iter().map(|uuid| PaneKey::Peer(uuid.clone())));
+        entries
+    }
+
+    fn label(&self, key: &PaneKey) -> String {
+        match key {
+            PaneKey::System => "System".to_string(),
+            PaneKey::Broadcast => "Broadcast".to_string(),
+            PaneKey::Room(room) => format!("#{}", room),
+            PaneKey::Peer(uuid) if self.peers.iter().any(|(id, _)| id == uuid) => format!("● {}", self.name_of(uuid)),
+            PaneKey::Peer(uuid) => format!("○ {}", self.name_of(uuid)),
+        }
+    }
+
+    fn push(&mut self, key: PaneKey, line: String) {
+        let selected = key == self.selected;
+        let pane = self.panes.entry(key).or_default();
+        for line in line.lines() {
+            if pane.lines.len() == PANE_HISTORY {
+                pane.lines.pop_front();
+            }
+            pane.lines.push_back(line.to_string());
+            if !selected {
+                pane.unread += 1;
+            } else if pane.scroll > 0 {
+                // 向上翻看时保持画面不动
+                pane.scroll += 1;
+            }
+        }
+    }
+
+    fn select(&mut self, key: PaneKey) {
+        self.panes.entry(key.clone()).or_default().unread = 0;
+        self.selected = key;
+    }
+
+    fn cycle(&mut self, forward: bool) {
+        let entries = self.entries();
+        let current = entries.iter().position(|key| *key == self.selected).unwrap_or(0);
+        let next = if forward {
+            (current + 1) % entries.len()
+        } else {
+            (current + entries.len() - 1) % entries.len()
+        };
+        self.select(entries[next].clone());
+    }
+
+    fn scroll(&mut self, up: bool) {
+        let pane = self.panes.entry(self.selected.clone()).or_default();
+        pane.scroll = if up {
+            (pane.scroll + SCROLL_STEP).min(pane.lines.len())
+        } else {
+            pane.scroll.saturating_sub(SCROLL_STEP)
+        };
+    }
+
+    // Received messages go to their conversation, everything else to System
+    fn handle_event(&mut self, event: Event) {
+        match event {
+            Event::MessageReceived { conversation, sender, text, .. } => {
+                let line = format!("[{}] {}: {}", timestamp(), self.name_of(&sender), text);
+                self.push(conversation.into(), line);
+            },
+            other => self.push(PaneKey::System, other.to_string()),
+        }
+    }
+
+    async fn refresh(&mut self, node_manager: &Arc<Mutex<NodeManager>>) {
+        // NodeManager 忙时跳过这次刷新，不阻塞界面
+        let Ok(manager) = node_manager.try_lock() else {
+            return;
+        };
+        let mut peers: Vec<(String, String)> = manager.nodes.lock().await.iter()
+            .map(|(uuid, node)| (uuid.clone(), node.name().unwrap_or(short_uuid(uuid)).to_string()))
+            .collect();
+        peers.sort_by(|a, b| a.1.cmp(&b.1).then_with(|| a.0.cmp(&b.0)));
+        self.peers = peers;
+        self.rooms = manager.joined_rooms().await;
+    }
+
+    fn handle_key(&mut self, key: KeyEvent, command_handler: &Arc<CommandHandler>) {
+        let ctrl = key.modifiers.contains(KeyModifiers::CONTROL);
+        match key.code {
+            KeyCode::Char('c') if ctrl => self.quit = true,
+            KeyCode::Char('d') if ctrl && self.input.is_empty() => self.quit = true,
+            KeyCode::Enter => self.submit(command_handler),
+            KeyCode::Tab | KeyCode::Down => self.cycle(true),
+            KeyCode::BackTab | KeyCode::Up => self.cycle(false),
+            KeyCode::PageUp => self.scroll(true),
+            KeyCode::PageDown => self.scroll(false),
+            KeyCode::Esc => {
+                self.input.clear();
+                self.cursor = 0;
+            },
+            KeyCode::Left => self.cursor = self.cursor.saturating_sub(1),
+            KeyCode::Right => self.cursor = (self.cursor + 1).min(self.input.chars().count()),
+            KeyCode::Home => self.cursor = 0,
+            KeyCode::End => self.cursor = self.input.chars().count(),
+            KeyCode::Backspace if self.cursor > 0 => {
+                self.cursor -= 1;
+                self.remove_char();
+            },
+            KeyCode::Delete if self.cursor < self.input.chars().count() => self.remove_char(),
+            KeyCode::Char(c) if !ctrl => {
+                let index = self.byte_index();
+                self.input.insert(index, c);
+                self.cursor += 1;
+            },
+            _ => {},
+        }
+    }
+
+    fn byte_index(&self) -> usize {
+        self.input.char_indices().nth(self.cursor).map(|(i, _)| i).unwrap_or(self.input.len())
+    }
+
+    fn remove_char(&mut self) {
+        let index = self.byte_index();
+        self.input.remove(index);
+    }
+
+    // Text typed in a conversation pane is sent there; a leading '/' (or the
+    // System pane) runs a terminal command instead
+    fn submit(&mut self, command_handler: &Arc<CommandHandler>) {
+        let line = std::mem::take(&mut self.input);
+        self.cursor = 0;
+        let line = line.trim().to_string();
+        if line.is_empty() {
+            return;
+        }
+        let command = match (line.strip_prefix('/'), &self.selected) {
+            (Some(command), _) => command.trim().to_string(),
+            (None, PaneKey::System) => line,
+            (None, key) => {
+                let key = key.clone();
+                // 与 send_message 等命令一样处理 \n、\t 转义
+                let line = command_registry::unescape(&line);
+                self.push(key.clone(), format!("[{}] me: {}", timestamp(), line));
+                let handler = Arc::clone(command_handler);
+                tokio::spawn(async move {
+                    match key {
+                        PaneKey::Peer(uuid) => handler.send_message(&uuid, &line).await,
+                        PaneKey::Room(room) => handler.say(&room, &line).await,
+                        PaneKey::Broadcast => handler.broadcast(&line).await,
+                        PaneKey::System => {},
+                    }
+                });
+                return;
+            },
+        };
+        if command_registry::is_exit(&command) {
+            self.quit = true;
+            return;
+        }
+        self.push(PaneKey::System, format!("> {}", terminal::masked(&command)));
+        let handler = Arc::clone(command_handler);
+        tokio::spawn(async move {
+            terminal::process_command(&command, handler).await.await;
+        });
+    }
+
+    fn draw(&mut self, frame: &mut Frame) {
+        let [body, status] = Layout::vertical([Constraint::Min(5), Constraint::Length(1)]).areas(frame.area());
+        let [sidebar, main] = Layout::horizontal([Constraint::Length(SIDEBAR_WIDTH), Constraint::Min(20)]).areas(body);
+        let [messages, input] = Layout::vertical([Constraint::Min(3), Constraint::Length(3)]).areas(main);
+
+        self.draw_sidebar(frame, sidebar);
+        self.draw_messages(frame, messages);
+        self.draw_input(frame, input);
+        frame.render_widget(
+            Line::from("Tab/Shift-Tab switch  PgUp/PgDn scroll  /command runs a command  Ctrl-C quit")
+                .style(Style::default().add_modifier(Modifier::DIM)),
+            status,
+        );
+    }
+
+    fn draw_sidebar(&self, frame: &mut Frame, area: Rect) {
+        let entries = self.entries();
+        let items: Vec<ListItem> = entries.iter()
+            .map(|key| {
+                let unread = self.panes.get(key).map_or(0, |pane| pane.unread);
+                let label = self.label(key);
+                let mut item = ListItem::new(if unread > 0 { format!("{} ({})", label, unread) } else { label });
+                if unread > 0 {
+                    item = item.style(Style::default().add_modifier(Modifier::BOLD));
+                }
+                item
+            })
+            .collect();
+        let mut state = ListState::default().with_selected(entries.iter().position(|key| *key == self.selected));
+        let list = List::new(items)
+            .block(Block::bordered().title(format!("Peers ({} online)", self.peers.len())))
+            .highlight_style(Style::default().add_modifier(Modifier::REVERSED));
+        frame.render_stateful_widget(list, area, &mut state);
+    }
+
+    fn draw_messages(&mut self, frame: &mut Frame, area: Rect) {
+        let title = self.label(&self.selected);
+        let width = area.width.saturating_sub(2) as usize;
+        let height = area.height.saturating_sub(2) as usize;
+        let pane = self.panes.entry(self.selected.clone()).or_default();
+        let wrapped: Vec<String> = pane.lines.iter().flat_map(|line| wrap(line, width)).collect();
+        pane.scroll = pane.scroll.min(wrapped.len().saturating_sub(height));
+        let end = wrapped.len() - pane.scroll;
+        let start = end.saturating_sub(height);
+        let title = if pane.scroll > 0 {
+            format!("{} (scrolled back {} lines)", title, pane.scroll)
+        } else {
+            title
+        };
+        let lines: Vec<Line> = wrapped[start..end].iter().map(|line| Line::from(line.as_str())).collect();
+        frame.render_widget(Paragraph::new(lines).block(Block::bordered().title(title)), area);
+    }
+
+    fn draw_input(&self, frame: &mut Frame, area: Rect) {
+        let hint = match &self.selected {
+            PaneKey::System => "Command".to_string(),
+            key => format!("Message {} (start with / for a command)", self.label(key)),
+        };
+        // 输入过长时只显示光标附近的部分
+        let (visible, column) = input_window(&self.input, self.cursor, area.width.saturating_sub(2) as usize);
+        frame.render_widget(Paragraph::new(visible).block(Block::bordered().title(hint)), area);
+        frame.set_cursor_position(Position::new(area.x + 1 + column as u16, area.y + 1));
+    }
+}
+
+// Full-screen interface used instead of run_terminal with --tui
+pub async fn run_tui(node: &ChatNode, events: EventStream) -> io::Result<()> {
+    let command_handler = Arc::new(CommandHandler::new(node.node_manager(), node.interfaces().to_vec()));
+    let (output_tx, output_rx) = mpsc::unbounded_channel();
+    console::redirect(output_tx);
+
+    // crossterm 读取事件是阻塞调用，放到单独的线程里
+    let (input_tx, input_rx) = mpsc::unbounded_channel();
+    std::thread::spawn(move || loop {
+        match event::poll(std::time::Duration::from_millis(100)) {
+            Ok(true) => match event::read() {
+                Ok(input) => {
+                    if input_tx.send(input).is_err() {
+                        break;
+                    }
+                },
+                Err(_) => break,
+            },
+            Ok(false) if input_tx.is_closed() => break,
+            Ok(false) => {},
+            Err(_) => break,
+        }
+    });
+
+    let mut terminal = ratatui::init();
+    let result = event_loop(&mut terminal, App::new(node.id()), command_handler, node.node_manager(), input_rx, output_rx, events).await;
+    ratatui::restore();
+    result
+}
+
+async fn event_loop(
+    terminal: &mut DefaultTerminal,
+    mut app: App,
+    command_handler: Arc<CommandHandler>,
+    node_manager: Arc<Mutex<NodeManager>>,
+    mut input_rx: mpsc::UnboundedReceiver<InputEvent>,
+    mut output_rx: mpsc::UnboundedReceiver<String>,
+    mut events: EventStream,
+) -> io::Result<()> {
+    let mut refresh = time::interval(REFRESH_INTERVAL);
+    while !app.quit {
+        terminal.draw(|frame| app.draw(frame))?;
+        tokio::select! {
+            Some(input) = input_rx.recv() => {
+                if let InputEvent::Key(key) = input {
+                    if key.kind == KeyEventKind::Press {
+                        app.handle_key(key, &command_handler);
+                    }
+                }
+            },
+            Some(line) = output_rx.recv() => app.push(PaneKey::System, line),
+            event = events.next() => match event {
+                Some(event) => app.handle_event(event),
+                None => app.quit = true,
+            },
+            _ = refresh.tick() => app.refresh(&node_manager).await,
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wraps_by_display_width() {
+        assert_eq!(wrap("abcdef", 4), vec!["abcd", "ef"]);
+        assert_eq!(wrap("你好世界", 5), vec!["你好", "世界"]);
+        assert_eq!(wrap("a你好", 4), vec!["a你", "好"]);
+        assert_eq!(wrap("", 4), vec![""]);
+    }
+
+    #[test]
+    fn input_window_keeps_the_cursor_in_view() {
+        assert_eq!(input_window("你好", 2, 10), ("你好".to_string(), 4));
+        assert_eq!(input_window("你好世界", 4, 5), ("世界".to_string(), 4));
+        assert_eq!(input_window("你好世界", 1, 5), ("你好".to_string(), 2));
+        assert_eq!(input_window("abcdef", 6, 4), ("def".to_string(), 3));
+        assert_eq!(input_window("", 0, 4), (String::new(), 0));
+    }
+
+    #[test]
+    fn echoed_commands_hide_passphrases() {
+        assert_eq!(terminal::masked("join lobby open sesame"), "join lobby ********");
+        assert_eq!(terminal::masked("join lobby"), "join lobby");
+        assert_eq!(terminal::masked("msg bob join us"), "msg bob join us");
+    }
+}
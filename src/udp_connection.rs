@@ -1,22 +1,23 @@
+use crate::console::output;
+use crate::network::Interface;
 use tokio::net::UdpSocket;
 use std::sync::Arc;
 use tokio::sync::mpsc;
-use tokio::sync::Mutex;
 use std::net::{SocketAddrV4, Ipv4Addr, SocketAddr};
 use std::io;
 
-pub async fn start_listening(socket: Arc<Mutex<UdpSocket>>, sender: mpsc::Sender<Vec<u8>>) -> io::Result<()> {
-    let mut buf = vec![0; 1024];
+// 最大的 UDP 数据报长度，接收缓冲区按此分配避免截断
+pub const RECV_BUFFER_SIZE: usize = 65536;
+
+pub async fn start_listening(socket: Arc<UdpSocket>, sender: mpsc::Sender<(Vec<u8>, SocketAddr)>) -> io::Result<()> {
+    let mut buf = vec![0; RECV_BUFFER_SIZE];
 
     loop {
-        let (len, _addr) = {
-            let mut socket = socket.lock().await;
-            socket.recv_from(&mut buf).await?
-        };
+        let (len, addr) = socket.recv_from(&mut buf).await?;
 
         // 将接收到的数据发送到通道
-        if sender.send(buf[..len].to_vec()).await.is_err() {
-            println!("Failed to send message to NodeManager");
+        if sender.send((buf[..len].to_vec(), addr)).await.is_err() {
+            output!("Failed to send message to NodeManager");
             break;
         }
     }
@@ -24,23 +25,42 @@ pub async fn start_listening(socket: Arc<Mutex<UdpSocket>>, sender: mpsc::Sender
     Ok(())
 }
 
-pub async fn send_message(ip: Ipv4Addr, port: u16, message: &str) -> io::Result<()> {
+// The chat sockets every datagram to a peer is sent from. With interfaces
+// chosen there is one per interface, so nothing leaves through another one.
+#[derive(Clone)]
+pub struct ChatSockets {
+    sockets: Arc<Vec<(Arc<UdpSocket>, Option<Interface>)>>,
+}
 
-    let ip = if ip == Ipv4Addr::new(0, 0, 0, 0) {
-        Ipv4Addr::new(127, 0, 0, 1)
-    } else {
-        ip
-    };
-    // 使用传入的 IP 和端口构建 SocketAddr
-    let remote_addr = SocketAddr::V4(SocketAddrV4::new(ip, port));
+impl ChatSockets {
+    pub fn new(sockets: Vec<(UdpSocket, Option<Interface>)>) -> Self {
+        let sockets = sockets.into_iter().map(|(socket, iface)| (Arc::new(socket), iface)).collect();
+        ChatSockets { sockets: Arc::new(sockets) }
+    }
 
-    // 创建一个新的 UdpSocket 并绑定到随机端口
-    let socket = UdpSocket::bind("0.0.0.0:0").await?;
+    // Each socket, for its listener
+    pub fn sockets(&self) -> Vec<Arc<UdpSocket>> {
+        self.sockets.iter().map(|(socket, _)| Arc::clone(socket)).collect()
+    }
 
-    // 发送消息到指定的远程地址
-    socket.send_to(message.as_bytes(), &remote_addr).await?;
+    pub async fn send_message(&self, ip: Ipv4Addr, port: u16, message: &[u8]) -> io::Result<()> {
+        let remote_addr = SocketAddr::V4(SocketAddrV4::new(ip, port));
+        // 组播从每个网卡各发一次，单播从与对方同一子网的网卡发出
+        let sockets: Vec<&UdpSocket> = if ip.is_multicast() {
+            self.sockets.iter().map(|(socket, _)| socket.as_ref()).collect()
+        } else {
+            let socket = self.sockets.iter()
+                .find(|(_, iface)| iface.as_ref().is_some_and(|iface| iface.contains(ip)))
+                .or(self.sockets.first())
+                .map(|(socket, _)| socket.as_ref());
+            socket.into_iter().collect()
+        };
+        for socket in sockets {
+            socket.send_to(message, &remote_addr).await?;
+        }
 
-    println!("Message sent to {}", remote_addr);
+        log::info!("Message sent to {}", remote_addr);
 
-    Ok(())
-}
\ No newline at end of file
+        Ok(())
+    }
+}